@@ -0,0 +1,9 @@
+#!/bin/bash
+set -e
+
+cargo clippy --all-targets -- -D warnings
+cargo test
+
+# NOTE: the examples' binaries embed their wasm blob, so only their libs can be checked without
+# building it first
+(cd examples/hello_server && cargo clippy --lib -- -D warnings)
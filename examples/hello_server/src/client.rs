@@ -1,48 +1,12 @@
-use pserve::client::{use_signal, use_state_event};
+use pserve::client::{Reply, use_rpc, use_server_error, use_signal, use_state_event};
 use pserve::dom::*;
-use pserve::signal::Signal;
-
-use crate::{CheckBoxStateEvent, ClientEvent, MemeListStateEvent, NUMBER_OF_CHECKBOXES};
-
-macro_rules! component_handler {
-    ($($name:literal => $component:ident),* $(,)?) => {
-        // TODO: figure out away to not have to make users create this function themselves
-        #[unsafe(no_mangle)]
-        extern "C" fn js_render_component(fn_name: *mut u8, len: usize) -> *const u8 {
-            let fn_name = unsafe { String::from_raw_parts(fn_name, len, len) };
-
-            match render_component(&fn_name) {
-                Some(html) => unsafe {
-                    pserve::client::RENDER_RESULT = pserve::client::RenderResult::from(html);
-                    &raw const pserve::client::RENDER_RESULT as *const _ as *const u8
-                },
-                None => std::ptr::null(),
-            }
-        }
 
-        fn render_component(msg: &str) -> Option<String> {
-            pserve::client::env::log(&msg);
-
-            match msg {
-                $($name => {
-                    let built = $component().build(
-                        &mut pserve::client::PERSISTENT_VALUES.get_builders_mut(),
-                        &mut pserve::client::PERSISTENT_VALUES.get_built_nodes_mut(),
-                        true,
-                    );
-
-                    Some(pserve::client::render_multi(built))
-                })*
-                _ => {
-                    pserve::client::env::log("unknown component");
-                    None
-                }
-            }
-        }
-    };
-}
+use crate::{
+    AddMeme, CheckBoxStateEvent, ClientEvent, MemeListStateEvent, NUMBER_OF_CHECKBOXES,
+    RenderComponent, SearchMemes, ToggleCheckBox,
+};
 
-component_handler! {
+pserve::component_handler! {
     "home_page" => home_page,
     "meme_list" => meme_list,
     "server_communicator" => server_communicator,
@@ -51,6 +15,7 @@ component_handler! {
 
 fn server_communicator() -> DomNodeBuilder {
     let input = use_signal(|| "".to_string());
+    let alert = use_signal(|| "".to_string());
 
     DomNodeBuilder::default()
         .push("p", || {
@@ -65,13 +30,17 @@ fn server_communicator() -> DomNodeBuilder {
         .on_input(move |value| input.set(value.to_string()))
         .push("button", || "Send to EVERYBODY".into())
         .on_click(move |_| {
-            let msg = ClientEvent::RenderComponent {
+            let msg = ClientEvent::RenderComponent(RenderComponent {
                 component_name: input.get(),
                 dom_id: None,
-            };
+            });
 
             pserve::client::env::send_event_to_server(&msg).unwrap();
         })
+        .push("input", || "".into())
+        .on_input(move |value| alert.set(value.to_string()))
+        .push("button", || "Alert EVERYBODY".into())
+        .on_click(move |_| pserve::client::env::alert(&alert.get()).unwrap())
 }
 
 fn home_page() -> DomNodeBuilder {
@@ -80,7 +49,7 @@ fn home_page() -> DomNodeBuilder {
     let show_meme_list = use_signal(|| false);
 
     DomNodeBuilder::default()
-        .push("div", || server_communicator())
+        .push("div", server_communicator)
         .push("strong", || "List of things".into())
         .push("ul", move || {
             let mut n = DomNodeBuilder::default();
@@ -113,7 +82,7 @@ fn home_page() -> DomNodeBuilder {
                 .on_click(move |_| show_meme_list.set(!show_meme_list.get()));
 
             if show_meme_list.get() {
-                n = n.push("span", move || meme_list())
+                n = n.push("span", meme_list)
             }
 
             n
@@ -128,6 +97,9 @@ fn home_page() -> DomNodeBuilder {
 fn meme_list() -> DomNodeBuilder {
     let memes = use_state_event(MemeListStateEvent);
     let meme_entry = use_signal(|| "".to_string());
+    let server_error = use_server_error();
+    let search_entry = use_signal(|| "".to_string());
+    let search = use_rpc::<SearchMemes>();
 
     DomNodeBuilder::default()
         .push("p", || "Meme list".into())
@@ -149,14 +121,39 @@ fn meme_list() -> DomNodeBuilder {
         .on_input(move |value| meme_entry.set(value.to_string()))
         .push("button", || "Add meme".into())
         .on_click(move |_| {
+            server_error.set(None);
+
             let new_meme = meme_entry.get();
-            pserve::client::env::send_event_to_server(&ClientEvent::AddMeme { meme: new_meme })
-                .unwrap();
+            pserve::client::env::send_event_to_server(&ClientEvent::AddMeme(AddMeme {
+                meme: new_meme,
+            }))
+            .unwrap();
 
             // TODO: support inline pushing
             // memes.get_mut().push(new_meme);
             meme_entry.set("".to_string());
         })
+        .push("p", move || match server_error.get() {
+            Some(error) if error.correlation.as_deref() == Some("AddMeme") => {
+                error.message.as_str().into()
+            }
+            _ => "".into(),
+        })
+        .push("input", || "".into())
+        .on_input(move |value| search_entry.set(value.to_string()))
+        .push("button", || "Search".into())
+        .on_click(move |_| {
+            search.call(&SearchMemes {
+                query: search_entry.get(),
+            })
+        })
+        .push("p", move || match search.get() {
+            Reply::Idle => "".into(),
+            Reply::Pending => "Searching...".into(),
+            Reply::Ok(found) if found.is_empty() => "Nothing found".into(),
+            Reply::Ok(found) => format!("Found {}", found.join(", ")).into(),
+            Reply::Err(error) => error.message.as_str().into(),
+        })
 }
 
 fn checkboxes() -> DomNodeBuilder {
@@ -174,13 +171,10 @@ fn checkboxes() -> DomNodeBuilder {
             });
 
         for i in 0..NUMBER_OF_CHECKBOXES {
-            let check_boxes = check_boxes.clone();
-
             n = n.push("div", move || {
                 let mut n = DomNodeBuilder::default();
 
                 for j in 0..NUMBER_OF_CHECKBOXES {
-                    let check_boxes = check_boxes.clone();
                     n = n.push("span", move || {
                         let mut n = DomNodeBuilder::default()
                             .push("input", || "".into())
@@ -190,9 +184,9 @@ fn checkboxes() -> DomNodeBuilder {
                                     "click {}",
                                     i * NUMBER_OF_CHECKBOXES + j
                                 ));
-                                let msg = ClientEvent::ToggleCheckBox {
+                                let msg = ClientEvent::ToggleCheckBox(ToggleCheckBox {
                                     id: (i * NUMBER_OF_CHECKBOXES + j) as u32,
-                                };
+                                });
 
                                 pserve::client::env::send_event_to_server(&msg).unwrap();
                             });
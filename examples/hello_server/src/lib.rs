@@ -1,26 +1,25 @@
-#[cfg(target_arch = "wasm32")]
 pub mod client;
 
 #[cfg(not(target_arch = "wasm32"))]
-use pserve::server::{Event, ToClientEvent};
+use pserve::server::{Ctx, Event, EventError, ToClientEvent};
 
-use pserve::state::{MultipleValueUpdate, Stateful, Valuable};
+use pserve::protocol::Rpc;
+use pserve::state::MultipleValueUpdate;
 
 use serde::{Deserialize, Serialize};
-use std::hash::Hash;
-use std::net::SocketAddr;
 
 pub const NUMBER_OF_CHECKBOXES: usize = 100;
 
+#[derive(Serialize, Deserialize)]
 pub struct State {
-    check_boxes: [bool; NUMBER_OF_CHECKBOXES * NUMBER_OF_CHECKBOXES],
+    check_boxes: Vec<bool>,
     meme_list: Vec<String>,
 }
 
 impl Default for State {
     fn default() -> Self {
         Self {
-            check_boxes: [false; NUMBER_OF_CHECKBOXES * NUMBER_OF_CHECKBOXES],
+            check_boxes: vec![false; NUMBER_OF_CHECKBOXES * NUMBER_OF_CHECKBOXES],
             meme_list: vec![
                 "React".to_string(),
                 "Rust".to_string(),
@@ -33,16 +32,38 @@ impl Default for State {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientEvent {
-    RenderComponent {
-        component_name: String,
-        dom_id: Option<u32>,
-    },
-    ToggleCheckBox {
-        id: u32,
-    },
-    AddMeme {
-        meme: String,
-    },
+    RenderComponent(RenderComponent),
+    ToggleCheckBox(ToggleCheckBox),
+    AddMeme(AddMeme),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenderComponent {
+    pub component_name: String,
+    pub dom_id: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ToggleCheckBox {
+    pub id: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddMeme {
+    pub meme: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchMemes {
+    pub query: String,
+}
+
+impl Rpc for SearchMemes {
+    type Response = Vec<String>;
+
+    fn method() -> &'static str {
+        "search_memes"
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -80,49 +101,36 @@ impl pserve::state::Stateful for MemeListStateEvent {
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-pub fn request_full_state(state: &mut State, who: SocketAddr, name: String) -> Option<Event> {
-    // Some(Event::ToSpecificClient {
-    //     who,
-    //     event: ToClientEvent::Custom {
-    //         event: todo!(), //serde_json::to_value(FullState::from_name(state, name)).unwrap(),
-    //     },
-    // })
-
+pub fn request_full_state(
+    state: &mut State,
+    ctx: Ctx,
+    name: String,
+) -> Result<Option<Event>, EventError> {
     match name.as_str() {
-        "memeList" => Some(Event::ToSpecificClient {
-            who,
-            event: MemeListStateEvent::as_full_update(&state.meme_list),
-        }),
-        "checkBoxes" => Some(Event::ToSpecificClient {
-            who,
-            event: CheckBoxStateEvent::as_full_update(&state.check_boxes),
+        "memeList" => ctx.reply(MemeListStateEvent::as_full_update(&state.meme_list)),
+        "checkBoxes" => ctx.reply(CheckBoxStateEvent::as_full_update(&state.check_boxes)),
+        "mySuperCoolSingleValueStateEvent" => ctx.reply(ToClientEvent::Custom {
+            event: serde_json::to_value("Hello, I'm different".to_string()).unwrap(),
         }),
-        "mySuperCoolSingleValueStateEvent" => Some(Event::ToSpecificClient {
-            who,
-            event: ToClientEvent::Custom {
-                event: serde_json::to_value("Hello, I'm different".to_string()).unwrap(),
-            },
-        }),
-        _ => None,
+        _ => {
+            return Err(EventError::new(
+                "unknown_state",
+                format!("there's no state called `{name}`"),
+            ));
+        }
     }
+
+    Ok(None)
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-// TODO: #[processor]
 pub fn render_component_for_everyone(
     _: &mut State,
-    _who: SocketAddr,
-    value: serde_json::Value, /* event: ClientEvent */
+    _ctx: Ctx,
+    RenderComponent { component_name, .. }: RenderComponent,
 ) -> Option<Event> {
-    pserve::server::tracing::info!("{:?}", value);
-    let event: ClientEvent = serde_json::from_value(value).unwrap();
-
-    let ClientEvent::RenderComponent { component_name, .. } = event else {
-        return None;
-    };
-
     Some(Event::ToAllClients(ToClientEvent::RenderComponent {
-        component_name: component_name.clone(),
+        component_name,
         dom_id: None,
         params: None,
     }))
@@ -131,37 +139,60 @@ pub fn render_component_for_everyone(
 #[cfg(not(target_arch = "wasm32"))]
 pub fn toggle_check_box(
     state: &mut State,
-    _who: SocketAddr,
-    value: serde_json::Value,
+    _ctx: Ctx,
+    ToggleCheckBox { id }: ToggleCheckBox,
 ) -> Option<Event> {
-    pserve::server::tracing::info!("toggle_check_box: {:?}", value);
-    let event: ClientEvent = serde_json::from_value(value).unwrap();
-
-    let ClientEvent::ToggleCheckBox { id } = event else {
-        return None;
-    };
-
-    state.check_boxes[id as usize] = !state.check_boxes[id as usize];
+    let check_box = state.check_boxes.get_mut(id as usize)?;
+    *check_box = !*check_box;
 
     Some(Event::ToAllClients(CheckBoxStateEvent::as_update(
-        id,
-        state.check_boxes[id as usize],
+        id, *check_box,
     )))
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-pub fn add_meme(state: &mut State, _who: SocketAddr, value: serde_json::Value) -> Option<Event> {
-    pserve::server::tracing::info!("add_meme: {:?}", value);
-    let event: ClientEvent = serde_json::from_value(value).unwrap();
-
-    let ClientEvent::AddMeme { meme } = event else {
-        return None;
-    };
+pub fn add_meme(
+    state: &mut State,
+    _ctx: Ctx,
+    AddMeme { meme }: AddMeme,
+) -> Result<Option<Event>, EventError> {
+    if meme.trim().is_empty() {
+        return Err(EventError::new("empty_meme", "a meme needs some text"));
+    }
 
     state.meme_list.push(meme.clone());
 
-    Some(Event::ToAllClients(MemeListStateEvent::as_update(
+    Ok(Some(Event::ToAllClients(MemeListStateEvent::as_update(
         state.meme_list.len() as u32 - 1,
         meme,
-    )))
+    ))))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn search_memes(
+    state: &mut State,
+    _ctx: Ctx,
+    SearchMemes { query }: SearchMemes,
+) -> Result<Vec<String>, EventError> {
+    if query.trim().is_empty() {
+        return Err(EventError::new("empty_query", "search for something"));
+    }
+
+    let query = query.to_lowercase();
+    Ok(state
+        .meme_list
+        .iter()
+        .filter(|meme| meme.to_lowercase().contains(&query))
+        .cloned()
+        .collect())
+}
+
+/// Anyone can alert everyone, as long as they have something to say.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn alert_everyone(_: &mut State, _ctx: Ctx, msg: String) -> Result<Option<Event>, EventError> {
+    if msg.trim().is_empty() {
+        return Err(EventError::new("empty_alert", "an alert needs some text"));
+    }
+
+    Ok(Some(Event::ToAllClients(ToClientEvent::Alert { msg })))
 }
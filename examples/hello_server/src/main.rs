@@ -1,3 +1,4 @@
+use pserve::server::{Shell, SlowClientPolicy, WireFormat};
 use pserve::server::tokio;
 use pserve::server::tracing;
 use pserve::server::tracing_subscriber::{self, layer::SubscriberExt, util::SubscriberInitExt};
@@ -16,20 +17,49 @@ async fn main() {
 
     tracing::info!("Hello, world!");
 
-    pserve::server::App::default()
+    let app = pserve::server::App::default();
+
+    #[cfg(debug_assertions)]
+    let app = app.assets("/static", concat!(env!("CARGO_MANIFEST_DIR"), "/static"));
+    #[cfg(not(debug_assertions))]
+    let app = app.embedded_assets("/static", pserve::embed_assets!("static", ["style.css"]));
+
+    let metrics = app.metrics();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            tracing::info!(metrics = ?metrics.snapshot());
+        }
+    });
+
+    app
         .wasm(include_bytes!(
             "../target/wasm32-unknown-unknown/debug/hello_server.wasm"
         ))
         .state_processor(hello_server::request_full_state)
-        .add_processor(render_component_for_everyone)
-        .add_processor(toggle_check_box)
-        .add_processor(add_meme)
+        .alert_processor(hello_server::alert_everyone)
+        .on(render_component_for_everyone)
+        .on(toggle_check_box)
+        .on(add_meme)
+        .rpc(hello_server::search_memes)
         .route("/", "home_page")
         .route("/meme_list", "meme_list")
         .route("/server_communicator", "server_communicator")
         .route("/checkboxes", "checkboxes")
+        .ssr(hello_server::client::render_component)
+        .wire_format(WireFormat::MessagePack)
+        .slow_clients(100, SlowClientPolicy::Coalesce)
+        .shell(
+            Shell::new(include_str!("shell.html"))
+                .title("Hello World")
+                .head(r#"<link rel="stylesheet" href="/static/style.css">"#),
+        )
         .state(hello_server::State::default())
-        .serve()
+        .persist("snapshot.json", std::time::Duration::from_secs(30))
+        .journal("journal.jsonl")
+        .serve_with_shutdown(async { tokio::signal::ctrl_c().await.unwrap() })
         .await
         .unwrap();
+
+    tracing::info!("shut down");
 }
@@ -1,60 +1,17 @@
 use dotenvy_macro::dotenv;
-use pserve::client::{use_cookie, use_signal};
+use pserve::client::{Reply, use_cookie, use_rpc, use_signal, use_state_event};
 use pserve::dom::DomNodeBuilder;
+use pserve::state::SettableEvent;
+use serde::Deserialize;
 
-use crate::{ClientEvent, UserInfoStateEvent};
+use crate::{DiscordLogin, UserInfoStateEvent};
 
-macro_rules! component_handler {
-    ($($name:literal => $component:ident),* $(,)?) => {
-        // TODO: figure out away to not have to make users create this function themselves
-        #[unsafe(no_mangle)]
-        extern "C" fn js_render_component(fn_name: *mut u8, fn_len: usize, params: *mut u8, params_len: usize) -> *const u8 {
-            let fn_name = unsafe { String::from_raw_parts(fn_name, fn_len, fn_len) };
-            let params = unsafe { String::from_raw_parts(params, params_len, params_len) };
-
-            match render_component(&fn_name, &params) {
-                Some(html) => unsafe {
-                    pserve::client::RENDER_RESULT = pserve::client::RenderResult::from(html);
-                    &raw const pserve::client::RENDER_RESULT as *const _ as *const u8
-                },
-                None => std::ptr::null(),
-            }
-        }
-
-        fn render_component(msg: &str, params: &str) -> Option<String> {
-            pserve::client::env::log(&msg);
-
-            let params = if params.is_empty() {
-                None
-            } else {
-                Some(params.to_string())
-            };
-
-            match msg {
-                $($name => {
-                    let built = $component(params).build(
-                        &mut pserve::client::PERSISTENT_VALUES.get_builders_mut(),
-                        &mut pserve::client::PERSISTENT_VALUES.get_built_nodes_mut(),
-                        true,
-                    );
-
-                    Some(pserve::client::render_multi(built))
-                })*
-                _ => {
-                    pserve::client::env::log("unknown component");
-                    None
-                }
-            }
-        }
-    };
-}
-
-component_handler! {
+pserve::component_handler! {
     "home_page" => home_page,
     "auth" => auth,
 }
 
-fn home_page(params: Option<String>) -> DomNodeBuilder {
+fn home_page() -> DomNodeBuilder {
     let user = use_cookie(UserInfoStateEvent);
 
     DomNodeBuilder::default().push("div", move || {
@@ -74,27 +31,40 @@ fn home_page(params: Option<String>) -> DomNodeBuilder {
     })
 }
 
-fn auth(params: Option<String>) -> DomNodeBuilder {
+/// What discord redirects back with after the user authorizes the app
+#[derive(Deserialize)]
+struct AuthParams {
+    code: Option<String>,
+}
+
+fn auth(AuthParams { code }: AuthParams) -> DomNodeBuilder {
     let user = use_cookie(UserInfoStateEvent);
-    let params = use_signal(|| params.map(|p| p[1..].split('=').nth(1).unwrap().to_string()));
+    let login = use_rpc::<DiscordLogin>().on_reply(|reply| {
+        if let Ok(user) = reply {
+            // NOTE: goes through the cookie's `on_update`, so the cookie is set too
+            use_state_event(UserInfoStateEvent).set(serde_json::to_value(Some(user)).unwrap());
+        }
+    });
+    let code = use_signal(|| code);
 
     DomNodeBuilder::default().push("div", move || {
         if let Some(user) = user.get() {
-            home_page(None)
+            home_page()
         } else {
-            if let Some(code) = params.get() {
-                params.set(None);
-
-                pserve::client::env::send_event_to_server(&ClientEvent::DiscordLogin {
-                    code: code.to_string(),
-                })
-                .unwrap();
+            if let Some(pending) = code.get() {
+                code.set(None);
+                login.call(&DiscordLogin { code: pending });
+            }
 
-                DomNodeBuilder::default().push("p", || "Logging in...".into())
-            } else {
-                DomNodeBuilder::default().push("div", move || {
+            match login.get() {
+                Reply::Idle => DomNodeBuilder::default().push("div", move || {
                     DomNodeBuilder::default().push("p", || "No code provided".into())
-                })
+                }),
+                Reply::Pending | Reply::Ok(_) => {
+                    DomNodeBuilder::default().push("p", || "Logging in...".into())
+                }
+                Reply::Err(err) => DomNodeBuilder::default()
+                    .push("p", move || format!("Couldn't log in: {}", err.message).into()),
             }
         }
     })
@@ -1,13 +1,10 @@
-#[cfg(target_arch = "wasm32")]
 pub mod client;
 
-#[cfg(target_arch = "wasm32")]
 use pserve::client::CookieEvent;
+use pserve::protocol::Rpc;
 
 #[cfg(not(target_arch = "wasm32"))]
-use pserve::server::{Event, ToClientEvent, tokio};
-#[cfg(not(target_arch = "wasm32"))]
-use std::net::SocketAddr;
+use pserve::server::{Ctx, Event, EventError, SessionId, StateHandle};
 
 use pserve::state::{IsSingleValue, Stateful, Valuable};
 
@@ -18,12 +15,20 @@ use std::collections::HashMap;
 #[cfg(not(target_arch = "wasm32"))]
 #[derive(Default)]
 pub struct State {
-    pub connection_auth: HashMap<SocketAddr, DiscordUser>,
+    pub connection_auth: HashMap<SessionId, DiscordUser>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub enum ClientEvent {
-    DiscordLogin { code: String },
+pub struct DiscordLogin {
+    pub code: String,
+}
+
+impl Rpc for DiscordLogin {
+    type Response = DiscordUser;
+
+    fn method() -> &'static str {
+        "discord_login"
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -38,7 +43,6 @@ impl Stateful for UserInfoStateEvent {
     }
 }
 
-#[cfg(target_arch = "wasm32")]
 impl CookieEvent for UserInfoStateEvent {
     fn cookie_name() -> &'static str {
         "userInfo"
@@ -60,12 +64,7 @@ pub struct DiscordUser {
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-pub fn cookie_processor(
-    state: &mut State,
-    who: SocketAddr,
-    name: String,
-    value: String,
-) -> Option<Event> {
+pub fn cookie_processor(state: &mut State, ctx: Ctx, name: String, value: String) -> Option<Event> {
     if name == "userInfo" {
         let user: Option<DiscordUser> = serde_json::from_str(&value).unwrap();
 
@@ -73,7 +72,7 @@ pub fn cookie_processor(
             pserve::server::tracing::info!("got cookie {name}: {value:?}, now logging them out");
 
             // NOTE: this is where you would check your DB if the cookie is valid
-            state.connection_auth.insert(who, user.clone());
+            state.connection_auth.insert(ctx.who(), user.clone());
 
             // Some(Event::ToSpecificClient {
             //     who,
@@ -91,13 +90,18 @@ pub fn cookie_processor(
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-pub fn discord_login(
-    state: &mut State,
-    who: SocketAddr,
-    value: serde_json::Value,
-) -> Option<Event> {
-    let event: ClientEvent = serde_json::from_value(value).unwrap();
-    let ClientEvent::DiscordLogin { code } = event;
+pub fn forget_session(state: &mut State, ctx: Ctx) -> Option<Event> {
+    state.connection_auth.remove(&ctx.who());
+    None
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn discord_login(
+    state: StateHandle<State>,
+    ctx: Ctx,
+    DiscordLogin { code }: DiscordLogin,
+) -> Result<DiscordUser, EventError> {
+    let who = ctx.who();
 
     let mut data = HashMap::new();
 
@@ -108,57 +112,50 @@ pub fn discord_login(
     data.insert("code", &code);
     data.insert("redirect_uri", &redirect_uri);
 
-    let user: Result<_, Box<dyn std::error::Error>> = tokio::task::block_in_place(move || {
-        tokio::runtime::Handle::current().block_on(async move {
-            let client = reqwest::Client::new();
-
-            let text = client
-                .post("https://discord.com/api/oauth2/token")
-                .form(&data)
-                .send()
-                .await?
-                .text()
-                .await?;
-
-            let auth: Discord = serde_json::from_str(&text).inspect_err(|err| {
-                pserve::server::tracing::error!(?text, "error logging in: {err:?}");
-            })?;
-
-            let text = client
-                .get("https://discord.com/api/v10/users/@me")
-                .bearer_auth(&auth.access_token)
-                .send()
-                .await?
-                .text()
-                .await?;
-
-            let user: DiscordUser = serde_json::from_str(&text).inspect_err(|err| {
-                pserve::server::tracing::error!(?text, "error getting user: {err:?}");
-            })?;
-
-            Ok(user)
-        })
-    });
+    let user: Result<_, Box<dyn std::error::Error + Send + Sync>> = async {
+        let client = reqwest::Client::new();
+
+        let text = client
+            .post("https://discord.com/api/oauth2/token")
+            .form(&data)
+            .send()
+            .await?
+            .text()
+            .await?;
+
+        let auth: Discord = serde_json::from_str(&text).inspect_err(|err| {
+            pserve::server::tracing::error!(?text, "error logging in: {err:?}");
+        })?;
+
+        let text = client
+            .get("https://discord.com/api/v10/users/@me")
+            .bearer_auth(&auth.access_token)
+            .send()
+            .await?
+            .text()
+            .await?;
+
+        let user: DiscordUser = serde_json::from_str(&text).inspect_err(|err| {
+            pserve::server::tracing::error!(?text, "error getting user: {err:?}");
+        })?;
+
+        Ok(user)
+    }
+    .await;
 
     match user {
         Ok(user) => {
             pserve::server::tracing::info!("logged in as {user:?}");
 
-            // FIXME: currently no way to remove clients who have disconnected
-            state.connection_auth.insert(who, user.clone());
-
-            Some(Event::ToSpecificClient {
-                who,
-                event: UserInfoStateEvent::as_single_update(Some(user)),
-                //     ToClientEvent::Custom {
-                //     event: serde_json::to_value(StateUpdate::UserInfo { user: Some(user) })
-                //         .unwrap(),
-                // },
-            })
+            state
+                .update(|state| state.connection_auth.insert(who, user.clone()))
+                .await;
+
+            Ok(user)
         }
         Err(e) => {
             pserve::server::tracing::error!("error logging in: {e:?}");
-            return None;
+            Err(EventError::new("login_failed", "couldn't log in with discord"))
         }
     }
 }
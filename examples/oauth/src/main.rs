@@ -17,9 +17,11 @@ async fn main() {
             "../target/wasm32-unknown-unknown/debug/oauth.wasm"
         ))
         .cookie_processor(oauth::cookie_processor)
-        .add_processor(oauth::discord_login)
+        .rpc_async(oauth::discord_login)
+        .on_session_end(oauth::forget_session)
         .route("/", "home_page")
         .route("/auth", "auth")
+        .ssr(oauth::client::render_component)
         .state(oauth::State::default())
         .serve()
         .await
@@ -1,42 +1,83 @@
 extern crate alloc;
 
-use crate::dom::{DomNodeBuilt, DomNodeBuiltBody, DomNodeUnbuilt, DomNodeUnbuiltBody};
+use crate::dom::{DomNodeBuilder, DomNodeBuilt, DomNodeBuiltBody, DomNodeUnbuilt};
+use crate::protocol::{Rpc, ToServerEvent, WireFormat};
+use crate::route::{ParamsError, RouteParams};
 use crate::signal::{Signal, SignalData};
 use crate::state::{SettableEvent, StateEvent, StateInner, Stateful, Valuable};
 use core::{
     any::Any,
-    cell::{LazyCell, Ref, RefCell, RefMut},
+    cell::{Cell, LazyCell, Ref, RefCell, RefMut},
     panic::Location,
     sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
 };
 use serde::de::DeserializeOwned;
 use std::any::TypeId;
 use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
 use std::marker::PhantomData;
 
 pub mod env {
     use serde::Serialize;
 
+    use crate::protocol::{ToServerEvent, WireError, WireFormat};
+
+    #[cfg(target_arch = "wasm32")]
     mod env_js {
         #[link(wasm_import_module = "Env")]
         unsafe extern "C" {
-            pub fn alert(msg: *const u8, len: i32);
             pub fn log(msg: *const u8, len: i32);
+            pub fn now() -> f64;
 
             pub fn update_dom(dom_id: u32, html: *const u8, len: i32);
             pub fn update_cookie(msg: *const u8, len: i32);
             pub fn get_cookie(msg: *const u8, len: i32, cookie_len: *mut i32) -> *const u8;
 
             pub fn send_event_to_server(msg: *const u8, len: i32);
+            pub fn send_binary_to_server(msg: *const u8, len: i32);
+            pub fn handle_server_event(msg: *const u8, len: i32);
         }
     }
 
-    pub fn alert(msg: &str) {
-        unsafe { env_js::alert(msg.as_ptr(), msg.len() as i32) }
+    // NOTE: there's no browser to talk to when rendering on the server, so do nothing
+    #[cfg(not(target_arch = "wasm32"))]
+    mod env_js {
+        pub unsafe fn log(msg: *const u8, len: i32) {
+            let msg = unsafe { std::slice::from_raw_parts(msg, len as usize) };
+            tracing::debug!("[client]: {}", String::from_utf8_lossy(msg));
+        }
+        pub unsafe fn now() -> f64 {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|since| since.as_secs_f64() * 1000.0)
+                .unwrap_or_default()
+        }
+
+        pub unsafe fn update_dom(_dom_id: u32, _html: *const u8, _len: i32) {}
+        pub unsafe fn update_cookie(_msg: *const u8, _len: i32) {}
+        pub unsafe fn get_cookie(_msg: *const u8, _len: i32, _cookie_len: *mut i32) -> *const u8 {
+            std::ptr::null()
+        }
+
+        pub unsafe fn send_event_to_server(_msg: *const u8, _len: i32) {}
+        pub unsafe fn send_binary_to_server(_msg: *const u8, _len: i32) {}
+    }
+
+    /// Asks the server to alert other clients, it's up to its
+    /// [`App::alert_processor`](crate::server::App::alert_processor) who (if anyone) sees it.
+    pub fn alert(msg: &str) -> Result<(), WireError> {
+        send_event_to_server(&ToServerEvent::Alert {
+            msg: msg.to_string(),
+        })
     }
     pub fn log(msg: &str) {
         unsafe { env_js::log(msg.as_ptr(), msg.len() as i32) }
     }
+    /// Milliseconds since the unix epoch, like JS's `Date.now()`.
+    pub fn now() -> f64 {
+        unsafe { env_js::now() }
+    }
     pub fn update_dom(dom_id: u32, html: &str) {
         unsafe { env_js::update_dom(dom_id, html.as_ptr(), html.len() as i32) }
     }
@@ -58,12 +99,27 @@ pub mod env {
         log(&format!("got cookie {cookie:?}"));
         Some(cookie)
     }
-    pub fn send_event_to_server<T: Serialize>(msg: &T) -> Result<(), serde_json::Error> {
-        let msg = serde_json::to_string(msg)?;
-        unsafe { env_js::send_event_to_server(msg.as_ptr(), msg.len() as i32) }
+    /// Encoded however the current connection negotiated, see [`WireFormat`].
+    pub fn send_event_to_server<T: Serialize>(msg: &T) -> Result<(), WireError> {
+        let format = super::PERSISTENT_VALUES.with(|values| values.wire_format.get());
+        let msg = format.encode(msg)?;
+        match format {
+            WireFormat::Json => unsafe {
+                env_js::send_event_to_server(msg.as_ptr(), msg.len() as i32)
+            },
+            WireFormat::MessagePack => unsafe {
+                env_js::send_binary_to_server(msg.as_ptr(), msg.len() as i32)
+            },
+        }
 
         Ok(())
     }
+    /// Hands an event that touches the page back to the JS glue.
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) fn handle_server_event(event: &crate::protocol::ToClientEvent) {
+        let event = serde_json::to_string(event).unwrap();
+        unsafe { env_js::handle_server_event(event.as_ptr(), event.len() as i32) }
+    }
 }
 
 // TODO: delete this dumb thing, OR AT LEAST make it a RefCell, _definitely_ causes memory corruption
@@ -72,6 +128,103 @@ pub static mut RENDER_RESULT: RenderResult = RenderResult {
     len: 0,
 };
 
+/// Generates the glue that renders components by name, both for the JS runtime and for
+/// [`App::ssr`](crate::server::App::ssr) as `render_component`.
+///
+/// Components either take no arguments or a single `DeserializeOwned` params type, which is
+/// decoded from the route's segments and query string, see [`RouteParams::decode`]. A page
+/// whose params don't decode shows the [`ParamsError`] instead, unless the component takes
+/// `Result<P, ParamsError>` and decides what to show itself.
+#[macro_export]
+macro_rules! component_handler {
+    ($($name:literal => $component:path),* $(,)?) => {
+        #[cfg(target_arch = "wasm32")]
+        #[unsafe(no_mangle)]
+        extern "C" fn js_render_component(fn_name: *mut u8, fn_len: usize, params: *mut u8, params_len: usize) -> *const u8 {
+            let fn_name = unsafe { String::from_raw_parts(fn_name, fn_len, fn_len) };
+            let params = unsafe { String::from_raw_parts(params, params_len, params_len) };
+            let Some(params) = $crate::client::parse_route_params(&params) else {
+                return std::ptr::null();
+            };
+
+            match render_component(&fn_name, &params) {
+                Some(html) => unsafe {
+                    $crate::client::RENDER_RESULT = $crate::client::RenderResult::from(html);
+                    &raw const $crate::client::RENDER_RESULT as *const _ as *const u8
+                },
+                None => std::ptr::null(),
+            }
+        }
+
+        pub fn render_component(name: &str, params: &$crate::route::RouteParams) -> Option<String> {
+            match name {
+                $($name => $crate::client::render_component_with(name, params, $component),)*
+                _ => {
+                    $crate::client::env::log(&format!("unknown component `{name}`"));
+                    None
+                }
+            }
+        }
+    };
+}
+
+/// A function that builds a component, see [`component_handler!`].
+pub trait Component<M> {
+    fn render(&self, params: &RouteParams) -> Result<DomNodeBuilder, ParamsError>;
+}
+
+impl<F: Fn() -> DomNodeBuilder> Component<()> for F {
+    fn render(&self, _: &RouteParams) -> Result<DomNodeBuilder, ParamsError> {
+        Ok(self())
+    }
+}
+
+impl<F: Fn(P) -> DomNodeBuilder, P: DeserializeOwned> Component<(P,)> for F {
+    fn render(&self, params: &RouteParams) -> Result<DomNodeBuilder, ParamsError> {
+        params.decode().map(self)
+    }
+}
+
+impl<F, P> Component<(P, ParamsError)> for F
+where
+    F: Fn(Result<P, ParamsError>) -> DomNodeBuilder,
+    P: DeserializeOwned,
+{
+    fn render(&self, params: &RouteParams) -> Result<DomNodeBuilder, ParamsError> {
+        Ok(self(params.decode()))
+    }
+}
+
+#[doc(hidden)]
+pub fn parse_route_params(params: &str) -> Option<RouteParams> {
+    serde_json::from_str(params)
+        .inspect_err(|err| env::log(&format!("invalid route params: {err}")))
+        .ok()
+}
+
+#[doc(hidden)]
+pub fn render_component_with<M>(
+    name: &str,
+    params: &RouteParams,
+    component: impl Component<M>,
+) -> Option<String> {
+    let builder = component.render(params).unwrap_or_else(|err| {
+        let msg = format!("can't render `{name}`: {err}");
+        env::log(&msg);
+        DomNodeBuilder::from(msg)
+    });
+
+    let built = PERSISTENT_VALUES.with(|values| {
+        builder.build(
+            &mut values.get_builders_mut(),
+            &mut values.get_built_nodes_mut(),
+            true,
+        )
+    });
+
+    Some(render_multi(built))
+}
+
 #[repr(C)]
 pub struct RenderResult {
     ptr: *const u8,
@@ -89,12 +242,14 @@ impl From<String> for RenderResult {
     }
 }
 
+#[cfg(target_arch = "wasm32")]
 #[unsafe(no_mangle)]
 extern "C" fn alloc_string(len: i32) -> u32 {
     String::with_capacity(len as usize).leak().as_ptr() as u32
 }
 
 // TODO: don't actually send the function pointer between javascript and wasm
+#[cfg(target_arch = "wasm32")]
 #[unsafe(no_mangle)]
 extern "C" fn call_fn_ptr(value: *mut u8, len: i32, ptr: *const Box<dyn Fn(&str)>) {
     let value = unsafe { String::from_raw_parts(value, len as usize, len as usize) };
@@ -103,6 +258,7 @@ extern "C" fn call_fn_ptr(value: *mut u8, len: i32, ptr: *const Box<dyn Fn(&str)
     func(&value);
 }
 
+#[cfg(target_arch = "wasm32")]
 #[unsafe(no_mangle)]
 extern "C" fn handle_custom_event(value: *mut u8, len: i32) {
     let value = unsafe { String::from_raw_parts(value, len as usize, len as usize) };
@@ -114,18 +270,184 @@ extern "C" fn handle_custom_event(value: *mut u8, len: i32) {
         }
     };
 
-    let mut event_subscriptions = PERSISTENT_VALUES.event_subscriptions.borrow_mut();
-    for event in event_subscriptions.values_mut() {
-        event.set(json_value.clone());
+    set_custom_event(json_value);
+}
+
+/// Called by the JS glue for errors on JSON connections, MessagePack ones go through
+/// [`handle_binary_event`].
+#[cfg(target_arch = "wasm32")]
+#[unsafe(no_mangle)]
+extern "C" fn handle_error_event(value: *mut u8, len: i32) {
+    let value = unsafe { String::from_raw_parts(value, len as usize, len as usize) };
+
+    match serde_json::from_str(&value) {
+        Ok(crate::protocol::ToClientEvent::Error {
+            code,
+            message,
+            correlation,
+        }) => set_server_error(ServerError {
+            code,
+            message,
+            correlation,
+        }),
+        Ok(_) => env::log(&format!("expected an error event: {value}")),
+        Err(e) => env::log(&format!("failed to deserialize error event: {e}: {value}")),
     }
 }
 
+/// Called by the JS glue for responses on JSON connections, like [`handle_error_event`].
+#[cfg(target_arch = "wasm32")]
 #[unsafe(no_mangle)]
-extern "C" fn rerender() {
-    for dom_id in PERSISTENT_VALUES.to_re_render.borrow_mut().drain() {
+extern "C" fn handle_response_event(value: *mut u8, len: i32) {
+    let value = unsafe { String::from_raw_parts(value, len as usize, len as usize) };
+
+    match serde_json::from_str(&value) {
+        Ok(crate::protocol::ToClientEvent::Response { id, payload }) => {
+            resolve_request(id, Ok(payload))
+        }
+        Ok(_) => env::log(&format!("expected a response event: {value}")),
+        Err(e) => env::log(&format!(
+            "failed to deserialize response event: {e}: {value}"
+        )),
+    }
+}
+
+/// Called by the JS glue every second, gives up on requests the server never answered.
+#[cfg(target_arch = "wasm32")]
+#[unsafe(no_mangle)]
+extern "C" fn expire_requests() {
+    let now = env::now();
+    let expired: Vec<u32> = PERSISTENT_VALUES.with(|values| {
+        values
+            .pending_requests
+            .borrow()
+            .iter()
+            .filter(|(_, request)| request.expires_at <= now)
+            .map(|(id, _)| *id)
+            .collect()
+    });
+
+    for id in expired {
+        resolve_request(
+            id,
+            Err(ServerError {
+                code: "timeout".to_string(),
+                message: "the server didn't answer in time".to_string(),
+                correlation: Some(id.to_string()),
+            }),
+        );
+    }
+}
+
+/// Called by the JS glue for every binary message, which only MessagePack connections get.
+#[cfg(target_arch = "wasm32")]
+#[unsafe(no_mangle)]
+extern "C" fn handle_binary_event(bytes: *mut u8, len: i32) {
+    let bytes = unsafe { Vec::from_raw_parts(bytes, len as usize, len as usize) };
+
+    match WireFormat::MessagePack.decode(&bytes) {
+        Ok(crate::protocol::ToClientEvent::Custom { event }) => set_custom_event(event),
+        Ok(crate::protocol::ToClientEvent::Response { id, payload }) => {
+            resolve_request(id, Ok(payload))
+        }
+        Ok(crate::protocol::ToClientEvent::Error {
+            code,
+            message,
+            correlation,
+        }) => set_server_error(ServerError {
+            code,
+            message,
+            correlation,
+        }),
+        // NOTE: everything else touches the page, which the JS glue already knows how to do
+        Ok(event) => env::handle_server_event(&event),
+        Err(e) => env::log(&format!("failed to decode binary event: {e}")),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn set_custom_event(value: serde_json::Value) {
+    PERSISTENT_VALUES.with(|values| {
+        let mut event_subscriptions = values.event_subscriptions.borrow_mut();
+        for event in event_subscriptions.values_mut() {
+            event.set(value.clone());
+        }
+    });
+}
+
+#[cfg(target_arch = "wasm32")]
+fn set_server_error(error: ServerError) {
+    env::log(&format!("server error: {error:?}"));
+
+    // NOTE: a failed request is only the business of whoever made it
+    let request = error.correlation.as_deref().and_then(|id| id.parse().ok());
+    match request {
+        Some(id)
+            if PERSISTENT_VALUES
+                .with(|values| values.pending_requests.borrow().contains_key(&id)) =>
         {
-            let mut builders = crate::client::PERSISTENT_VALUES.get_builders_mut();
-            let mut built_nodes = crate::client::PERSISTENT_VALUES.get_built_nodes_mut();
+            resolve_request(id, Err(error))
+        }
+        _ => use_server_error().set(Some(error)),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn resolve_request(id: u32, result: Result<serde_json::Value, ServerError>) {
+    let request = PERSISTENT_VALUES.with(|values| values.pending_requests.borrow_mut().remove(&id));
+    match request {
+        Some(request) => (request.resolve)(result),
+        None => env::log(&format!(
+            "got a reply to request {id} after giving up on it"
+        )),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[unsafe(no_mangle)]
+extern "C" fn set_wire_format(protocol: *mut u8, len: i32) {
+    let protocol = unsafe { String::from_raw_parts(protocol, len as usize, len as usize) };
+
+    let format = WireFormat::from_protocol(&protocol).unwrap_or_default();
+    PERSISTENT_VALUES.with(|values| values.wire_format.set(format));
+}
+
+/// Called by the JS glue after the websocket reconnects, the server has forgotten about
+/// everything this client cared about so tell it again.
+#[cfg(target_arch = "wasm32")]
+#[unsafe(no_mangle)]
+extern "C" fn resubscribe() {
+    PERSISTENT_VALUES.with(|values| {
+        for name in values.cookies.borrow().iter() {
+            if let Some(value) = env::get_cookie(name) {
+                env::send_event_to_server(&ToServerEvent::Cookie {
+                    name: name.to_string(),
+                    value,
+                })
+                .unwrap();
+            }
+        }
+
+        for event in values.event_subscriptions.borrow().values() {
+            env::send_event_to_server(&ToServerEvent::RequestFullState {
+                name: event.name().to_string(),
+            })
+            .unwrap();
+        }
+    });
+}
+
+#[cfg(target_arch = "wasm32")]
+#[unsafe(no_mangle)]
+extern "C" fn rerender() {
+    use crate::dom::DomNodeUnbuiltBody;
+
+    let to_re_render =
+        PERSISTENT_VALUES.with(|values| std::mem::take(&mut *values.to_re_render.borrow_mut()));
+    for dom_id in to_re_render {
+        PERSISTENT_VALUES.with(|values| {
+            let mut builders = values.get_builders_mut();
+            let mut built_nodes = values.get_built_nodes_mut();
 
             // TODO: don't just duplicate what dom.rs does
             if let Some(node) = builders.remove(&dom_id) {
@@ -174,7 +496,7 @@ extern "C" fn rerender() {
 
                 builders.insert(dom_id, node);
             }
-        }
+        });
 
         let html = render(dom_id);
         env::update_dom(dom_id, &html);
@@ -184,9 +506,23 @@ extern "C" fn rerender() {
 pub struct PersistentState {
     cell: LazyCell<RefCell<HashMap<Location<'static>, Box<dyn Any>>>>,
     event_subscriptions: LazyCell<RefCell<HashMap<TypeId, Box<dyn SettableEvent>>>>,
+    cookies: LazyCell<RefCell<HashSet<&'static str>>>,
     builders: LazyCell<RefCell<HashMap<u32, DomNodeUnbuilt>>>,
     built_nodes: LazyCell<RefCell<HashMap<u32, DomNodeBuilt>>>,
     pub(crate) to_re_render: LazyCell<RefCell<HashSet<u32>>>,
+    allocations: LazyCell<RefCell<Vec<Deallocation>>>,
+    wire_format: Cell<WireFormat>,
+    server_error: Cell<Option<Signal<Option<ServerError>, ()>>>,
+    pending_requests: LazyCell<RefCell<HashMap<u32, PendingRequest>>>,
+}
+
+type Deallocation = Box<dyn FnOnce()>;
+
+/// A request sent with [`RpcCall::call`] that hasn't been answered yet.
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+struct PendingRequest {
+    expires_at: f64,
+    resolve: Box<dyn FnOnce(Result<serde_json::Value, ServerError>)>,
 }
 
 impl PersistentState {
@@ -215,18 +551,174 @@ fn use_signal_with_caller<T: Clone + 'static>(
     f: impl FnOnce() -> T,
     location: Location<'static>,
 ) -> Signal<T, ()> {
-    let mut signal = persist_value(
-        || Signal {
-            inner: Box::into_raw(Box::new(SignalData::new(f()))),
-        },
-        location,
-    );
+    let mut signal = persist_value(|| alloc_signal(SignalData::new(f())), location);
 
     signal.reset();
 
     signal
 }
 
+/// An error the server sent back, see [`use_server_error`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerError {
+    pub code: String,
+    pub message: String,
+    /// What the error is about if the server knows, e.g. the tag of the event that failed
+    pub correlation: Option<String>,
+}
+
+/// The last error the server sent back, e.g. because an event sent by this client failed.
+///
+/// Shared by every component, set it back to `None` once it's been dealt with.
+pub fn use_server_error() -> Signal<Option<ServerError>, ()> {
+    if let Some(signal) = PERSISTENT_VALUES.with(|values| values.server_error.get()) {
+        return signal;
+    }
+
+    let signal = alloc_signal(SignalData::new(None));
+    PERSISTENT_VALUES.with(|values| values.server_error.set(Some(signal)));
+    signal
+}
+
+/// How long [`RpcCall::call`] waits for an answer.
+pub const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(10);
+
+static NEXT_REQUEST_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Where the last call made with an [`RpcCall`] is at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply<T> {
+    /// Nothing has been sent yet
+    Idle,
+    Pending,
+    Ok(T),
+    /// The server couldn't answer, or didn't in time (the `code` is `"timeout"` then)
+    Err(ServerError),
+}
+
+#[derive(Clone)]
+struct RpcState<T> {
+    id: u32,
+    reply: Reply<T>,
+    on_reply: Option<fn(&Result<T, ServerError>)>,
+}
+
+/// Sends requests of type `R` to the server and keeps track of the reply, see [`use_rpc`].
+pub struct RpcCall<R: Rpc>
+where
+    R::Response: Clone,
+{
+    state: Signal<RpcState<R::Response>, ()>,
+}
+
+impl<R: Rpc> Clone for RpcCall<R>
+where
+    R::Response: Clone,
+{
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<R: Rpc> Copy for RpcCall<R> where R::Response: Clone {}
+
+/// Calls the server's [`App::rpc`](crate::server::App::rpc) handler for `R`.
+///
+/// Reading [`RpcCall::get`] while rendering re-renders once the reply arrives. Only the reply to
+/// the latest call counts, older ones are ignored.
+#[track_caller]
+pub fn use_rpc<R: Rpc + 'static>() -> RpcCall<R>
+where
+    R::Response: Clone + 'static,
+{
+    let state = use_signal(|| RpcState {
+        id: 0,
+        reply: Reply::Idle,
+        on_reply: None,
+    });
+
+    RpcCall { state }
+}
+
+impl<R: Rpc + 'static> RpcCall<R>
+where
+    R::Response: Clone + 'static,
+{
+    pub fn get(&self) -> Reply<R::Response> {
+        self.state.get().reply
+    }
+
+    /// Runs `f` with the reply as soon as it arrives, before anything re-renders.
+    pub fn on_reply(mut self, f: fn(&Result<R::Response, ServerError>)) -> Self {
+        self.state.get_mut().on_reply = Some(f);
+        self
+    }
+
+    /// Waits [`DEFAULT_RPC_TIMEOUT`] for the reply.
+    pub fn call(&self, request: &R) {
+        self.call_with_timeout(request, DEFAULT_RPC_TIMEOUT);
+    }
+
+    pub fn call_with_timeout(&self, request: &R, timeout: Duration) {
+        let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::SeqCst);
+        let mut state = self.state;
+        let on_reply = state.get_mut().on_reply;
+        state.set(RpcState {
+            id,
+            reply: Reply::Pending,
+            on_reply,
+        });
+
+        PERSISTENT_VALUES.with(|values| {
+            values.pending_requests.borrow_mut().insert(
+                id,
+                PendingRequest {
+                    expires_at: env::now() + timeout.as_millis() as f64,
+                    resolve: Box::new(move |result| Self::resolve(state, id, result)),
+                },
+            )
+        });
+
+        env::send_event_to_server(&ToServerEvent::Request {
+            id,
+            method: R::method().to_string(),
+            payload: serde_json::to_value(request).unwrap(),
+        })
+        .unwrap();
+    }
+
+    fn resolve(
+        mut state: Signal<RpcState<R::Response>, ()>,
+        id: u32,
+        result: Result<serde_json::Value, ServerError>,
+    ) {
+        let current = state.get_mut().clone();
+        if current.id != id {
+            return;
+        }
+
+        let result = result.and_then(|payload| {
+            serde_json::from_value::<R::Response>(payload).map_err(|e| ServerError {
+                code: "invalid_response".to_string(),
+                message: e.to_string(),
+                correlation: Some(id.to_string()),
+            })
+        });
+
+        state.set(RpcState {
+            reply: match &result {
+                Ok(response) => Reply::Ok(response.clone()),
+                Err(err) => Reply::Err(err.clone()),
+            },
+            ..current
+        });
+
+        if let Some(on_reply) = current.on_reply {
+            on_reply(&result);
+        }
+    }
+}
+
 pub trait CookieEvent {
     fn cookie_name() -> &'static str;
 }
@@ -363,42 +855,36 @@ where
     StateEvent<T, M>: SettableEvent,
     <T as Stateful>::Data: DeserializeOwned + Default + Clone,
 {
-    let mut event_subscriptions = PERSISTENT_VALUES.event_subscriptions.borrow_mut();
-
-    if let Some(state_event) = event_subscriptions.get(&TypeId::of::<T>()) {
-        let state_event = (*state_event)
-            .as_any()
-            .downcast_ref::<StateEvent<T, M>>()
+    PERSISTENT_VALUES.with(|values| {
+        let mut event_subscriptions = values.event_subscriptions.borrow_mut();
+
+        if let Some(state_event) = event_subscriptions.get(&TypeId::of::<T>()) {
+            let state_event = (*state_event)
+                .as_any()
+                .downcast_ref::<StateEvent<T, M>>()
+                .unwrap();
+
+            state_event.clone()
+        } else {
+            let data = SignalData::new(StateInner {
+                inner: <T as Stateful>::Data::default(),
+                on_update: None,
+                _marker: PhantomData,
+            });
+            let state_event = StateEvent {
+                data: alloc_signal(data),
+            };
+
+            event_subscriptions.insert(TypeId::of::<T>(), Box::new(state_event.clone()));
+
+            env::send_event_to_server(&ToServerEvent::RequestFullState {
+                name: T::name().to_string(),
+            })
             .unwrap();
 
-        return state_event.clone();
-    } else {
-        let data = SignalData::new(StateInner {
-            inner: <T as Stateful>::Data::default(),
-            on_update: None,
-            _marker: PhantomData,
-        });
-        let state_event = StateEvent {
-            data: Signal {
-                inner: Box::into_raw(Box::new(data)),
-            },
-        };
-
-        event_subscriptions.insert(TypeId::of::<T>(), Box::new(state_event.clone()));
-
-        // TODO: don't hide to server event behind non-wasm arch flag
-        #[derive(serde::Serialize)]
-        #[serde(tag = "type", rename_all = "camelCase")]
-        enum Event {
-            RequestFullState { name: String },
+            state_event
         }
-        env::send_event_to_server(&Event::RequestFullState {
-            name: T::name().to_string(),
-        })
-        .unwrap();
-
-        state_event
-    }
+    })
 }
 
 // pub fn use_state_event<T>(event: T)
@@ -459,19 +945,15 @@ where
         // TODO: tell the server what cookie we have
         env::update_cookie(T::cookie_name(), value);
 
-        // TODO: don't hide to server event behind non-wasm arch flag
-        #[derive(serde::Serialize)]
-        #[serde(tag = "type", rename_all = "camelCase")]
-        enum Event {
-            Cookie { name: String, value: String },
-        }
-        env::send_event_to_server(&Event::Cookie {
+        env::send_event_to_server(&ToServerEvent::Cookie {
             name: T::cookie_name().to_string(),
             value: serde_json::to_string(&value).unwrap(),
         })
         .unwrap();
     });
 
+    PERSISTENT_VALUES.with(|values| values.cookies.borrow_mut().insert(T::cookie_name()));
+
     if let Some(cookie) = env::get_cookie(T::cookie_name()) {
         let value = serde_json::from_str(&cookie).unwrap();
         state_event.set(value);
@@ -480,31 +962,80 @@ where
     state_event
 }
 
-// NOTE: This is WASM so its probably fine lol
-unsafe impl Send for PersistentState {}
-unsafe impl Sync for PersistentState {}
-
 pub static NEXT_DOM_ID: AtomicU32 = AtomicU32::new(1);
 pub static CURRENT_SCOPE_DOM_ID: AtomicU32 = AtomicU32::new(0);
 
-pub static PERSISTENT_VALUES: PersistentState = PersistentState {
-    cell: LazyCell::new(|| RefCell::new(HashMap::new())),
-    event_subscriptions: LazyCell::new(|| RefCell::new(HashMap::new())),
-    builders: LazyCell::new(|| RefCell::new(HashMap::new())),
-    built_nodes: LazyCell::new(|| RefCell::new(HashMap::new())),
-    to_re_render: LazyCell::new(|| RefCell::new(HashSet::new())),
-};
+thread_local! {
+    /// Per thread since the server renders pages too, see `render_on_server`.
+    pub static PERSISTENT_VALUES: PersistentState = PersistentState {
+        cell: LazyCell::new(|| RefCell::new(HashMap::new())),
+        event_subscriptions: LazyCell::new(|| RefCell::new(HashMap::new())),
+        cookies: LazyCell::new(|| RefCell::new(HashSet::new())),
+        builders: LazyCell::new(|| RefCell::new(HashMap::new())),
+        built_nodes: LazyCell::new(|| RefCell::new(HashMap::new())),
+        to_re_render: LazyCell::new(|| RefCell::new(HashSet::new())),
+        allocations: LazyCell::new(|| RefCell::new(Vec::new())),
+        wire_format: Cell::new(WireFormat::Json),
+        server_error: Cell::new(None),
+        pending_requests: LazyCell::new(|| RefCell::new(HashMap::new())),
+    };
+}
 
-pub fn persist_value<T: Clone + 'static>(f: impl FnOnce() -> T, location: Location<'static>) -> T {
-    let mut values = PERSISTENT_VALUES.cell.borrow_mut();
+/// Signals are leaked on purpose since a page keeps them around until it's closed, but the
+/// server renders a fresh page for every request and has to clean up after itself.
+pub(crate) fn alloc_signal<T: Clone + 'static, K: Clone + Hash + Eq + 'static>(
+    data: SignalData<T, K>,
+) -> Signal<T, K> {
+    let inner = Box::into_raw(Box::new(data));
+
+    #[cfg(not(target_arch = "wasm32"))]
+    PERSISTENT_VALUES.with(|values| {
+        values
+            .allocations
+            .borrow_mut()
+            .push(Box::new(move || drop(unsafe { Box::from_raw(inner) })))
+    });
+
+    Signal { inner }
+}
 
-    if let Some(value) = values.get(&location) {
-        value.downcast_ref::<T>().unwrap().clone()
-    } else {
-        let value = f();
-        values.insert(location, Box::new(value.clone()));
-        value
+/// Throws away everything rendered so far and starts over with a blank page.
+///
+/// Used when rendering on the server, any signal from before this call is dangling afterwards.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn reset() {
+    let allocations = PERSISTENT_VALUES.with(|values| {
+        values.builders.borrow_mut().clear();
+        values.built_nodes.borrow_mut().clear();
+        values.to_re_render.borrow_mut().clear();
+        values.event_subscriptions.borrow_mut().clear();
+        values.cookies.borrow_mut().clear();
+        values.cell.borrow_mut().clear();
+        values.server_error.set(None);
+        values.pending_requests.borrow_mut().clear();
+
+        std::mem::take(&mut *values.allocations.borrow_mut())
+    });
+    for free in allocations {
+        free();
     }
+
+    NEXT_DOM_ID.store(1, Ordering::SeqCst);
+    set_current_dom_id(0);
+}
+
+pub fn persist_value<T: Clone + 'static>(f: impl FnOnce() -> T, location: Location<'static>) -> T {
+    PERSISTENT_VALUES.with(|values| {
+        let mut values = values.cell.borrow_mut();
+
+        if let Some(value) = values.get(&location) {
+            value.downcast_ref::<T>().unwrap().clone()
+        } else {
+            let value = f();
+            values.insert(location, Box::new(value.clone()));
+            value
+        }
+    })
 }
 
 pub fn next_dom_id() -> u32 {
@@ -529,56 +1060,61 @@ pub fn render_multi(dom_ids: impl IntoIterator<Item = u32>) -> String {
 }
 
 pub fn render(dom_id: u32) -> String {
-    let mut string = String::new();
+    PERSISTENT_VALUES.with(|values| {
+        let mut string = String::new();
 
-    let built_nodes = &crate::client::PERSISTENT_VALUES.get_built_nodes();
-    let builders = &crate::client::PERSISTENT_VALUES.get_builders();
+        let built_nodes = &values.get_built_nodes();
+        let builders = &values.get_builders();
 
-    if let (Some(built_node), Some(builder)) = (built_nodes.get(&dom_id), builders.get(&dom_id)) {
+        if let (Some(built_node), Some(builder)) = (built_nodes.get(&dom_id), builders.get(&dom_id))
         {
-            if !builder.tag.is_empty() {
-                string.push_str(&format!("<{} data-pserve-id={}", builder.tag, dom_id));
-            }
+            {
+                if !builder.tag.is_empty() {
+                    string.push_str(&format!("<{} data-pserve-id={}", builder.tag, dom_id));
+                }
 
-            for (attr, value) in &builder.attributes {
-                if value.is_empty() {
-                    string.push_str(&format!(" {attr} "));
-                } else {
-                    string.push_str(&format!(" {attr}='{value}' "));
+                for (attr, value) in &builder.attributes {
+                    if value.is_empty() {
+                        string.push_str(&format!(" {attr} "));
+                    } else {
+                        string.push_str(&format!(" {attr}='{value}' "));
+                    }
                 }
-            }
 
-            if let Some(on_input) = &builder.on_input {
-                string.push_str(&format!(
-                    " oninput=\"call_wasm_fn_ptr(this.value, {})\"",
-                    on_input.as_ref() as *const Box<dyn Fn(&str)> as i32
-                ));
-            }
-            if let Some(on_click) = &builder.on_click {
-                string.push_str(&format!(
-                    " onclick=\"call_wasm_fn_ptr(this.value, {})\"",
-                    on_click.as_ref() as *const Box<dyn Fn(&str)> as i32
-                ));
-            }
+                #[cfg(target_arch = "wasm32")]
+                if let Some(on_input) = &builder.on_input {
+                    string.push_str(&format!(
+                        " oninput=\"call_wasm_fn_ptr(this.value, {})\"",
+                        on_input.as_ref() as *const Box<dyn Fn(&str)> as i32
+                    ));
+                }
+                #[cfg(target_arch = "wasm32")]
+                if let Some(on_click) = &builder.on_click {
+                    string.push_str(&format!(
+                        " onclick=\"call_wasm_fn_ptr(this.value, {})\"",
+                        on_click.as_ref() as *const Box<dyn Fn(&str)> as i32
+                    ));
+                }
 
-            if !builder.tag.is_empty() {
-                string.push('>');
+                if !builder.tag.is_empty() {
+                    string.push('>');
+                }
             }
-        }
 
-        match &built_node.body {
-            DomNodeBuiltBody::Text(text) => string.push_str(text),
-            DomNodeBuiltBody::Nodes(nodes) => {
-                for node_id in nodes {
-                    string.push_str(&render(*node_id));
+            match &built_node.body {
+                DomNodeBuiltBody::Text(text) => string.push_str(text),
+                DomNodeBuiltBody::Nodes(nodes) => {
+                    for node_id in nodes {
+                        string.push_str(&render(*node_id));
+                    }
                 }
             }
-        }
 
-        if !builder.tag.is_empty() {
-            string.push_str(&format!("</{}>", builder.tag));
+            if !builder.tag.is_empty() {
+                string.push_str(&format!("</{}>", builder.tag));
+            }
         }
-    }
 
-    string
+        string
+    })
 }
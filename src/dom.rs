@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+pub type EventHandler = Box<Box<dyn Fn(&str)>>;
+
 #[derive(Default)]
 pub struct DomNodeBuilder {
     children: Vec<DomNodeUnbuilt>,
@@ -10,8 +12,8 @@ pub struct DomNodeUnbuilt {
     pub tag: &'static str,
     pub attributes: Vec<(String, String)>,
     pub body: Option<DomNodeUnbuiltBody>,
-    pub on_input: Option<Box<Box<dyn Fn(&str)>>>,
-    pub on_click: Option<Box<Box<dyn Fn(&str)>>>,
+    pub on_input: Option<EventHandler>,
+    pub on_click: Option<EventHandler>,
 }
 
 pub struct DomNodeBuilt {
@@ -32,10 +34,7 @@ pub enum DomNodeUnbuiltBody {
 impl DomNodeBuilder {
     pub fn push(mut self, tag: &'static str, body: impl Fn() -> DomNodeBuilder + 'static) -> Self {
         self.children.push(DomNodeUnbuilt {
-            #[cfg(target_arch = "wasm32")]
             id: crate::client::next_dom_id(),
-            #[cfg(not(target_arch = "wasm32"))]
-            id: 0,
             tag,
             attributes: Vec::new(),
             body: Some(DomNodeUnbuiltBody::Constructor(Box::new(Box::new(body)))),
@@ -90,13 +89,11 @@ impl DomNodeBuilder {
                         unbuilt_nodes.insert(child.id, child);
                     }
                     DomNodeUnbuiltBody::Constructor(ctor) if run_children => {
-                        #[cfg(target_arch = "wasm32")]
                         crate::client::set_current_dom_id(child.id);
 
                         let builder = ctor();
                         let child_body = builder.build(unbuilt_nodes, built_nodes, run_children);
 
-                        #[cfg(target_arch = "wasm32")]
                         crate::client::set_current_dom_id(0);
 
                         built_nodes.insert(
@@ -123,10 +120,7 @@ impl<T: AsRef<str>> From<T> for DomNodeBuilder {
     fn from(value: T) -> Self {
         Self {
             children: vec![DomNodeUnbuilt {
-                #[cfg(target_arch = "wasm32")]
                 id: crate::client::next_dom_id(),
-                #[cfg(not(target_arch = "wasm32"))]
-                id: 0,
                 tag: "",
                 attributes: Vec::new(),
                 body: Some(DomNodeUnbuiltBody::Text(value.as_ref().to_string())),
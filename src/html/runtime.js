@@ -0,0 +1,339 @@
+const PSERVE_BASE_PATH = {{base_path}};
+const PSERVE_WASM_URL = {{wasm_url}};
+const PSERVE_WIRE_PROTOCOL = {{wire_protocol}};
+const PSERVE_HEARTBEAT_INTERVAL = {{heartbeat_interval}};
+const PSERVE_HEARTBEAT_TIMEOUT = {{heartbeat_timeout}};
+
+const ws_url = new URL(PSERVE_BASE_PATH + "/ws", window.location.href);
+ws_url.protocol = ws_url.protocol === "https:" ? "wss:" : "ws:";
+
+// the shell decides where (and whether) to show these
+const set_status = (status) => {
+    document.querySelectorAll("[data-pserve-status]").forEach((e) => e.innerText = status);
+};
+
+let s;
+let has_connected = false;
+let reconnect_attempts = 0;
+let last_heard = 0;
+let stale = false;
+// messages sent while the socket is down, flushed once it's back up
+const outbox = [];
+// binary messages can only be decoded by the wasm, so they wait for it to load
+const pending_binary = [];
+
+const send = (msg) => {
+    if (s.readyState === WebSocket.OPEN) {
+        s.send(msg);
+    } else {
+        outbox.push(msg);
+    }
+};
+
+const connect = () => {
+    s = new WebSocket(ws_url, PSERVE_WIRE_PROTOCOL);
+    s.binaryType = "arraybuffer";
+    s.onopen = on_open;
+    s.onclose = on_close;
+    s.onmessage = on_message;
+};
+
+const on_open = () => {
+    set_status("Connected");
+    reconnect_attempts = 0;
+    last_heard = Date.now();
+    stale = false;
+    sync_wire_format();
+
+    // the server doesn't remember what we were subscribed to, so ask again
+    if (has_connected && !!instance) {
+        instance.exports.resubscribe();
+    }
+    has_connected = true;
+
+    while (outbox.length > 0) {
+        s.send(outbox.shift());
+    }
+};
+
+const on_close = () => {
+    set_status("Reconnecting");
+    stale = false;
+
+    const backoff = Math.min(10000, 250 * 2 ** reconnect_attempts);
+    reconnect_attempts += 1;
+    setTimeout(connect, backoff / 2 + Math.random() * backoff / 2);
+};
+
+// tells the wasm how to encode what it sends, the server picked a protocol when it accepted
+const sync_wire_format = () => {
+    if (!!instance && s.readyState === WebSocket.OPEN) {
+        const protocol_str = write_string(instance, s.protocol);
+        instance.exports.set_wire_format(protocol_str.ptr, protocol_str.len);
+    }
+};
+
+// the server sends a heartbeat every PSERVE_HEARTBEAT_INTERVAL, so silence means the connection
+// died without anyone noticing
+setInterval(() => {
+    // requests can time out whether or not the socket is up
+    if (!!instance) {
+        instance.exports.expire_requests();
+        instance.exports.rerender();
+    }
+
+    if (s.readyState !== WebSocket.OPEN) {
+        return;
+    }
+
+    const silent_for = Date.now() - last_heard;
+    if (silent_for > PSERVE_HEARTBEAT_TIMEOUT) {
+        // closing a dead socket can take ages, so reconnect without waiting for it
+        s.onclose = null;
+        s.onmessage = null;
+        s.close();
+        on_close();
+    } else if (silent_for > PSERVE_HEARTBEAT_INTERVAL * 1.5 && !stale) {
+        stale = true;
+        set_status("Stale");
+    }
+}, 1000);
+
+const on_message = (event) => {
+    last_heard = Date.now();
+    if (stale) {
+        stale = false;
+        set_status("Connected");
+    }
+
+    if (typeof event.data !== "string") {
+        if (!!instance) {
+            handle_binary_event(event.data);
+        } else {
+            pending_binary.push(event.data);
+        }
+        return;
+    }
+
+    try {
+        handle_event(JSON.parse(event.data));
+    } catch (e) {
+        console.error("failed to parse message", e);
+    };
+};
+
+const handle_event = (msg) => {
+    if (msg.type === "alert") {
+        alert(msg.msg);
+    } else if (msg.type === "domUpdate") {
+        const e = document.querySelector(`[data-pserve-id="${msg.domId}"]`);
+        e.innerHTML = msg.html;
+    } else if (msg.type === "renderComponent") {
+        renderComponentAt(instance, msg.componentName, msg.domId ?? "test", JSON.stringify(msg.params ?? {}));
+    } else if (msg.type === "error") {
+        console.error(`[pserve] ${msg.code}: ${msg.message}`);
+        if (!!instance) {
+            const msg_str = write_string(instance, JSON.stringify(msg));
+            instance.exports.handle_error_event(msg_str.ptr, msg_str.len);
+            instance.exports.rerender();
+        }
+    } else if (msg.type === "response") {
+        if (!!instance) {
+            const msg_str = write_string(instance, JSON.stringify(msg));
+            instance.exports.handle_response_event(msg_str.ptr, msg_str.len);
+            instance.exports.rerender();
+        }
+    } else if (msg.type === "heartbeat") {
+        send(JSON.stringify({type: "heartbeat"}));
+    } else if (msg.type === "custom") {
+        (async () => {
+            handle_custom_event(JSON.stringify(msg.event));
+        })();
+    }
+};
+
+connect();
+
+const write_u32 = (instance, ptr, num) => {
+    const view = new DataView(instance.exports.memory.buffer);
+    view.setUint32(ptr, num, true);
+};
+const read_string = (instance, ptr, len) => {
+    const bytes = new Uint8Array(instance.exports.memory.buffer, ptr, len);
+    return new TextDecoder("utf-8").decode(bytes);
+};
+const write_string = (instance, str) => {
+    const bytes = new TextEncoder("utf-8").encode(str);
+    const len = bytes.length;
+
+    const ptr = instance.exports.alloc_string(len);
+    const memory = instance.exports.memory;
+    const view = new DataView(memory.buffer);
+
+    for (let i = 0; i < bytes.length; i++) {
+        view.setUint8(ptr + i, bytes[i]);
+    }
+
+    return {ptr, len};
+};
+
+let instance;
+const memory = new WebAssembly.Memory({
+    initial: 10,
+    maximum: 100,
+});
+const importObj = {
+    Env: {
+        log: (ptr, len) => {
+            const msg = read_string(instance, ptr, len);
+
+            console.log(`[WASM]: ${msg}`);
+        },
+        now: () => Date.now(),
+        update_dom: (dom_id, ptr, len) => {
+            const msg = read_string(instance, ptr, len);
+            // s.send(JSON.stringify({type: "domUpdate", domId: dom_id, html: msg}));
+            const e = document.querySelector(`[data-pserve-id="${dom_id}"]`);
+            if (!!e) {
+                e.outerHTML = msg;
+            }
+        },
+        update_cookie: (ptr, len) => {
+            const msg = read_string(instance, ptr, len);
+            document.cookie = msg;
+        },
+        get_cookie: (ptr, len, cookie_len_ptr) => {
+            const msg = read_string(instance, ptr, len);
+            const cookie = document.cookie
+            .split(";")
+            .map((c) => c.trim())
+            .find((c) => c.startsWith(msg + "="))
+            ?.split("=")[1];
+
+            console.log(`[JS]: ${cookie}`);
+
+            if (!!cookie) {
+                write_u32(instance, cookie_len_ptr, cookie.length);
+                const cookie_str = write_string(instance, cookie);
+                console.log(`[JS]: ${cookie_str.ptr}`);
+                return cookie_str.ptr;
+            } else {
+                return 0;
+            }
+        },
+        send_event_to_server: (ptr, len) => {
+            const msg = read_string(instance, ptr, len);
+            send(msg);
+        },
+        send_binary_to_server: (ptr, len) => {
+            // NOTE: copied, the wasm is free to reuse its memory as soon as this returns
+            send(new Uint8Array(instance.exports.memory.buffer, ptr, len).slice());
+        },
+        handle_server_event: (ptr, len) => {
+            handle_event(JSON.parse(read_string(instance, ptr, len)));
+        },
+    },
+};
+
+(async () => {
+    const response = await fetch(PSERVE_WASM_URL);
+    const result = 
+        await WebAssembly.instantiateStreaming(response, importObj);
+    instance = result.instance;
+    console.log(instance);
+
+    document.querySelectorAll("[data-pserve-loading]").forEach((e) => e.remove());
+
+    sync_wire_format();
+    while (pending_binary.length > 0) {
+        handle_binary_event(pending_binary.shift());
+    }
+
+    // the server already rendered this page, so just hook the wasm up to it
+    const ssr_mount = document.querySelector("[data-pserve-ssr]");
+    if (!!ssr_mount) {
+        renderComponentAt(instance, ssr_mount.dataset.pserveSsr, "test", ssr_mount.dataset.pserveParams);
+    } else {
+        const path = window.location.pathname.slice(PSERVE_BASE_PATH.length) || "/";
+        send(JSON.stringify({type: "pageLoad", path, params: window.location.search}));
+    }
+})();
+
+function call_wasm_fn_ptr(value, ptr) {
+    const value_str = write_string(instance, value);
+    instance.exports.call_fn_ptr(...Object.values(value_str), ptr);
+    instance.exports.rerender();
+}
+
+function handle_binary_event(data) {
+    const bytes = new Uint8Array(data);
+    const ptr = instance.exports.alloc_string(bytes.length);
+    new Uint8Array(instance.exports.memory.buffer, ptr, bytes.length).set(bytes);
+
+    instance.exports.handle_binary_event(ptr, bytes.length);
+    instance.exports.rerender();
+}
+
+function handle_custom_event(msg) {
+    const msg_str = write_string(instance, msg);
+    instance.exports.handle_custom_event(...Object.values(msg_str));
+    instance.exports.rerender();
+}
+
+function renderComponentAt(instance, component_name, domId, params) {
+    const component_name_str = write_string(instance, component_name);
+    const params_str = write_string(instance, params);
+    const result_ptr = instance.exports.js_render_component(component_name_str.ptr, component_name_str.len, params_str.ptr, params_str.len);
+
+    if (result_ptr === 0) {
+        console.error("failed to render component");
+        return;
+    }
+    const view = new DataView(instance.exports.memory.buffer);
+    const str_ptr = view.getUint32(result_ptr, true);
+    const str_len = view.getInt32(result_ptr + 4, true);
+
+    const str = read_string(instance, str_ptr, str_len);
+    //console.log(str);
+
+    const e = document.querySelector(`[data-pserve-id="${domId}"]`);
+    if (e.hasAttribute("data-pserve-ssr")) {
+        e.removeAttribute("data-pserve-ssr");
+        hydrate(e, str);
+    } else {
+        e.innerHTML = str;
+    }
+}
+
+// adopts the nodes rendered by the server, all they're missing are the event handlers
+function hydrate(e, html) {
+    const template = document.createElement("template");
+    template.innerHTML = html;
+
+    // NOTE: the server doesn't render event handlers, everything else has to be the same, e.g. a
+    // cookie only the client knows about changes what gets rendered
+    const stripped = template.content.cloneNode(true);
+    stripped.querySelectorAll("[onclick], [oninput]").forEach((node) => {
+        node.removeAttribute("onclick");
+        node.removeAttribute("oninput");
+    });
+    const matches = stripped.childNodes.length === e.childNodes.length
+        && [...stripped.childNodes].every((node, i) => node.isEqualNode(e.childNodes[i]));
+
+    if (!matches) {
+        console.warn("server rendered html doesn't match, rendering again");
+        e.innerHTML = html;
+        return;
+    }
+
+    const rendered = template.content.querySelectorAll("[data-pserve-id]");
+    const existing = e.querySelectorAll("[data-pserve-id]");
+    rendered.forEach((node, i) => {
+        for (const attr of ["onclick", "oninput"]) {
+            const value = node.getAttribute(attr);
+            if (value !== null) {
+                existing[i].setAttribute(attr, value);
+            }
+        }
+    });
+}
@@ -1,8 +1,9 @@
 #[cfg(not(target_arch = "wasm32"))]
 pub mod server;
 
-#[cfg(target_arch = "wasm32")]
 pub mod client;
+pub mod protocol;
+pub mod route;
 
 pub mod signal;
 pub mod state;
@@ -0,0 +1,303 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::route::RouteParams;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ToServerEvent {
+    Test(String),
+    PageLoad {
+        path: String,
+        params: String,
+    },
+    RequestFullState {
+        name: String,
+    },
+    Cookie {
+        name: String,
+        value: String,
+    },
+    Alert {
+        msg: String,
+    },
+    Request {
+        id: u32,
+        method: String,
+        payload: serde_json::Value,
+    },
+    Heartbeat,
+    Custom(serde_json::Value),
+}
+
+impl ToServerEvent {
+    /// The `type` of every variant above, keep in sync.
+    const TAGS: &[&str] = &[
+        "test",
+        "pageLoad",
+        "requestFullState",
+        "cookie",
+        "alert",
+        "request",
+        "heartbeat",
+        "custom",
+    ];
+
+    /// Anything that isn't one of pserve's own events is a custom event for the processors.
+    pub fn decode(format: WireFormat, bytes: &[u8]) -> Result<Self, WireError> {
+        let err = match format.decode(bytes) {
+            Ok(event) => return Ok(event),
+            Err(err) => err,
+        };
+
+        let value: serde_json::Value = format.decode(bytes)?;
+        // NOTE: one of our own events with missing or mistyped fields is broken, not custom
+        let tag = value.get("type").and_then(serde_json::Value::as_str);
+        if tag.is_some_and(|tag| Self::TAGS.contains(&tag)) {
+            return Err(err);
+        }
+
+        Ok(ToServerEvent::Custom(value))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ToClientEvent {
+    /// Pops up `msg` in the browser, see
+    /// [`App::alert_processor`](crate::server::App::alert_processor)
+    Alert {
+        msg: String,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    DomUpdate {
+        dom_id: String,
+        html: String,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    RenderComponent {
+        component_name: String,
+        params: Option<RouteParams>,
+        dom_id: Option<String>,
+    },
+
+    Custom {
+        event: serde_json::Value,
+    },
+
+    /// Something the client sent was rejected or failed, `code` is meant for code and `message`
+    /// for people. `correlation` is what it was about if that's known, e.g. the tag of a custom
+    /// event or the name of a state
+    Error {
+        code: String,
+        message: String,
+        correlation: Option<String>,
+    },
+
+    /// The answer to the [`ToServerEvent::Request`] with the same `id`, only sent to the
+    /// connection that made it. Failed requests get a [`ToClientEvent::Error`] with the `id` as
+    /// its `correlation` instead
+    Response {
+        id: u32,
+        payload: serde_json::Value,
+    },
+
+    /// Sent every so often so both ends notice when the connection silently died, the client
+    /// answers with [`ToServerEvent::Heartbeat`]. See
+    /// [`App::heartbeat`](crate::server::App::heartbeat)
+    Heartbeat,
+}
+
+/// A request the client can wait on an answer to, made with
+/// [`use_rpc`](crate::client::use_rpc) and answered by a handler registered with
+/// [`App::rpc`](crate::server::App::rpc).
+///
+/// Lives wherever both the client and the server can see it, so they agree on the types.
+pub trait Rpc: Serialize + DeserializeOwned {
+    type Response: Serialize + DeserializeOwned;
+
+    /// What the server finds the handler by, has to be unique within the app.
+    fn method() -> &'static str;
+}
+
+/// How events are encoded on the websocket, picked by the client as a subprotocol when it
+/// connects. Text frames are always JSON, so plain JSON clients keep working either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl WireFormat {
+    pub const ALL: [WireFormat; 2] = [WireFormat::MessagePack, WireFormat::Json];
+
+    /// The websocket subprotocol a client asks for to get this format.
+    pub const fn protocol(self) -> &'static str {
+        match self {
+            WireFormat::Json => "pserve.json",
+            WireFormat::MessagePack => "pserve.msgpack",
+        }
+    }
+
+    pub fn from_protocol(protocol: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.protocol() == protocol)
+    }
+
+    /// Picks the first of the comma separated subprotocols a client `offered` that's a format,
+    /// JSON if it didn't offer any. `None` if it only offered ones this server can't speak.
+    pub fn negotiate(offered: &str) -> Option<Self> {
+        let mut offered = offered
+            .split(',')
+            .map(str::trim)
+            .filter(|protocol| !protocol.is_empty())
+            .peekable();
+
+        if offered.peek().is_none() {
+            return Some(WireFormat::Json);
+        }
+
+        offered.find_map(Self::from_protocol)
+    }
+
+    pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, WireError> {
+        match self {
+            WireFormat::Json => serde_json::to_vec(value).map_err(WireError::Json),
+            // NOTE: structs have to be maps, internally tagged enums can't be read back otherwise
+            WireFormat::MessagePack => {
+                rmp_serde::to_vec_named(value).map_err(WireError::MessagePackEncode)
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, WireError> {
+        match self {
+            WireFormat::Json => serde_json::from_slice(bytes).map_err(WireError::Json),
+            WireFormat::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(WireError::MessagePackDecode)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WireError {
+    Json(serde_json::Error),
+    MessagePackEncode(rmp_serde::encode::Error),
+    MessagePackDecode(rmp_serde::decode::Error),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::Json(err) => write!(f, "json: {err}"),
+            WireError::MessagePackEncode(err) => write!(f, "messagepack: {err}"),
+            WireError::MessagePackDecode(err) => write!(f, "messagepack: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for WireError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WireError::Json(err) => Some(err),
+            WireError::MessagePackEncode(err) => Some(err),
+            WireError::MessagePackDecode(err) => Some(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_json(json: &str) -> Result<ToServerEvent, WireError> {
+        ToServerEvent::decode(WireFormat::Json, json.as_bytes())
+    }
+
+    #[test]
+    fn every_variant_tag_is_known() {
+        let events = [
+            ToServerEvent::PageLoad {
+                path: "/".to_string(),
+                params: String::new(),
+            },
+            ToServerEvent::RequestFullState {
+                name: "count".to_string(),
+            },
+            ToServerEvent::Cookie {
+                name: "theme".to_string(),
+                value: "dark".to_string(),
+            },
+            ToServerEvent::Alert {
+                msg: "hi".to_string(),
+            },
+            ToServerEvent::Request {
+                id: 1,
+                method: "search".to_string(),
+                payload: serde_json::Value::Null,
+            },
+            ToServerEvent::Heartbeat,
+            ToServerEvent::Custom(serde_json::json!({})),
+        ];
+
+        for event in events {
+            let value = serde_json::to_value(&event).unwrap();
+            let tag = value["type"].as_str().unwrap();
+            assert!(ToServerEvent::TAGS.contains(&tag), "`{tag}` is missing");
+        }
+    }
+
+    #[test]
+    fn broken_builtin_event_is_invalid() {
+        assert!(decode_json(r#"{"type":"request","method":"search"}"#).is_err());
+        assert!(decode_json(r#"{"type":"alert","msg":1}"#).is_err());
+        assert!(
+            ToServerEvent::decode(
+                WireFormat::MessagePack,
+                &rmp_serde::to_vec_named(&serde_json::json!({ "type": "cookie" })).unwrap(),
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn negotiates_what_the_client_offered() {
+        assert_eq!(WireFormat::negotiate(""), Some(WireFormat::Json));
+        assert_eq!(
+            WireFormat::negotiate("pserve.msgpack"),
+            Some(WireFormat::MessagePack)
+        );
+        assert_eq!(
+            WireFormat::negotiate("chat, pserve.json , pserve.msgpack"),
+            Some(WireFormat::Json)
+        );
+        assert_eq!(WireFormat::negotiate("pserve.cbor"), None);
+        assert_eq!(WireFormat::negotiate("pserve.json;q=1"), None);
+    }
+
+    #[test]
+    fn anything_else_is_custom() {
+        for json in [
+            r#"{"type":"toggle","index":3}"#,
+            r#"{"Toggle":3}"#,
+            r#"{"type":7}"#,
+            r#""ping""#,
+        ] {
+            assert!(
+                matches!(decode_json(json), Ok(ToServerEvent::Custom(_))),
+                "{json}"
+            );
+        }
+
+        assert!(matches!(
+            decode_json(r#"{"type":"heartbeat"}"#),
+            Ok(ToServerEvent::Heartbeat)
+        ));
+    }
+}
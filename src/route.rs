@@ -0,0 +1,135 @@
+use std::{collections::HashMap, fmt};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+/// What the current page's url matched, handed to the component registered for the route.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteParams {
+    /// Named segments and wildcards of the pattern, `/user/{id}` matching `/user/42` gives
+    /// `id = "42"`
+    pub path: HashMap<String, String>,
+    /// The raw query string with its leading `?`, empty if there isn't one
+    pub query: String,
+}
+
+impl RouteParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.path.get(name).map(String::as_str)
+    }
+
+    /// Decodes the query string and the path segments into `P`, a path segment wins if both
+    /// have the same name.
+    ///
+    /// Values are decoded like a form, so `P` should be a struct of strings, numbers, bools and
+    /// `Option`s of those.
+    pub fn decode<P: DeserializeOwned>(&self) -> Result<P, ParamsError> {
+        let query = self.query.strip_prefix('?').unwrap_or(&self.query);
+
+        let mut pairs: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        pairs.extend(self.path.clone());
+
+        let encoded = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(&pairs)
+            .finish();
+
+        serde_urlencoded::from_str(&encoded).map_err(ParamsError)
+    }
+}
+
+/// The params of a page didn't fit what the component asked for.
+#[derive(Debug)]
+pub struct ParamsError(serde_urlencoded::de::Error);
+
+impl fmt::Display for ParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid route params: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParamsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct UserPage {
+        id: u32,
+        tab: Option<String>,
+        #[serde(default)]
+        compact: bool,
+    }
+
+    fn params(path: &[(&str, &str)], query: &str) -> RouteParams {
+        RouteParams {
+            path: path
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            query: query.to_string(),
+        }
+    }
+
+    #[test]
+    fn path_and_query() {
+        let page: UserPage = params(&[("id", "42")], "?tab=posts&compact=true")
+            .decode()
+            .unwrap();
+
+        assert_eq!(
+            page,
+            UserPage {
+                id: 42,
+                tab: Some("posts".to_string()),
+                compact: true,
+            }
+        );
+    }
+
+    #[test]
+    fn optional_fields_can_be_missing() {
+        let page: UserPage = params(&[("id", "7")], "").decode().unwrap();
+
+        assert_eq!(
+            page,
+            UserPage {
+                id: 7,
+                tab: None,
+                compact: false,
+            }
+        );
+    }
+
+    #[test]
+    fn path_wins_over_query() {
+        let page: UserPage = params(&[("id", "1")], "?id=2").decode().unwrap();
+
+        assert_eq!(page.id, 1);
+    }
+
+    #[test]
+    fn query_is_percent_decoded() {
+        let page: UserPage = params(&[("id", "1")], "tab=hello%20world%26more")
+            .decode()
+            .unwrap();
+
+        assert_eq!(page.tab.as_deref(), Some("hello world&more"));
+    }
+
+    #[test]
+    fn wrong_params() {
+        assert!(params(&[], "").decode::<UserPage>().is_err());
+        assert!(params(&[("id", "abc")], "").decode::<UserPage>().is_err());
+        assert!(
+            params(&[("id", "1")], "?compact=maybe")
+                .decode::<UserPage>()
+                .is_err()
+        );
+    }
+}
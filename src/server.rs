@@ -1,355 +1,1623 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet},
     net::SocketAddr,
     ops::{ControlFlow, Deref},
-    sync::Arc,
-    time::Duration,
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime},
 };
 
 use axum::{
     Router,
-    body::Bytes,
     extract::{
-        ConnectInfo, State, WebSocketUpgrade,
-        ws::{Message, WebSocket},
+        ConnectInfo, Path, State, WebSocketUpgrade,
+        ws::{CloseFrame, Message, WebSocket, close_code},
     },
-    response::{Html, IntoResponse},
+    http::{
+        HeaderMap, HeaderValue, StatusCode, Uri,
+        header::{SEC_WEBSOCKET_PROTOCOL, SET_COOKIE},
+    },
+    response::{Html, IntoResponse, Response},
     routing::get,
 };
-use axum_extra::{TypedHeader, headers, response::Wasm};
-use futures_util::{SinkExt, StreamExt};
-use serde::{Deserialize, Serialize};
-use tokio::sync::{
-    RwLock,
-    mpsc::{Receiver, Sender},
+use axum_extra::{TypedHeader, headers};
+use futures_util::{Future, FutureExt, SinkExt, StreamExt, future::BoxFuture};
+use percent_encoding::percent_decode_str;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::{
+    sync::{
+        RwLock, RwLockReadGuard, RwLockWriteGuard,
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        watch,
+    },
+    task::JoinSet,
 };
 use tower_http::trace::{DefaultMakeSpan, TraceLayer};
 
+mod assets;
+mod error;
+mod journal;
+mod limits;
+mod metrics;
+mod persist;
+mod queue;
+mod rpc;
+mod session;
+mod shell;
+#[cfg(test)]
+mod tests;
+mod typed;
+mod wasm;
+
+pub use crate::protocol::{Rpc, ToClientEvent, ToServerEvent, WireFormat};
+pub use error::{ConfigError, EventError, ProcessorOutput};
+pub use journal::{JournalEntry, ReplayError};
+pub use metrics::{Metrics, MetricsSnapshot};
+pub use persist::{MigrateFn, PersistError};
+pub use queue::SlowClientPolicy;
+pub use session::{ConnectionId, InvalidSessionId, SessionId};
+pub use shell::Shell;
 pub use tokio;
 pub use tracing;
 pub use tracing_subscriber;
+pub use typed::DispatchError;
+
+use crate::route::RouteParams;
+
+use assets::Assets;
+use journal::Journal;
+use limits::{Limiter, Limits};
+use persist::{Migrations, Persistence};
+use queue::ClientQueue;
+use rpc::RpcHandler;
+use session::{SESSION_COOKIE, SessionToken};
+use shell::escape_html;
+use wasm::WasmBlob;
+
+use typed::{EventTags, TypedHandler};
 
 // pub type StateProcessorFnDyn<T> = dyn Fn(&mut T, SocketAddr, String) -> Option<Event> + Send + Sync;
 // pub type ProcessorFnDyn<T> =
 //     dyn Fn(&mut T, SocketAddr, serde_json::Value) -> Option<Event> + Send + Sync;
 
-pub type StateProcessorFn<T> = fn(&mut T, SocketAddr, String) -> Option<Event>;
-pub type CookieProcessorFn<T> = fn(&mut T, SocketAddr, String, String) -> Option<Event>;
-pub type ProcessorFn<T> = fn(&mut T, SocketAddr, serde_json::Value) -> Option<Event>;
-
-struct ApiState<T: Send + Sync> {
-    events_to_be_sent: RwLock<VecDeque<Event>>,
-    connected_clients: RwLock<HashMap<SocketAddr, ConnectedClient>>,
-    state_processor: RwLock<Option<Box<StateProcessorFn<T>>>>,
-    cookie_processor: RwLock<Option<Box<CookieProcessorFn<T>>>>,
-    processors: RwLock<Vec<ProcessorFn<T>>>,
-    routes: RwLock<HashMap<String, String>>,
+pub type StateProcessorFn<T, R = Option<Event>> = fn(&mut T, Ctx, String) -> R;
+pub type CookieProcessorFn<T, R = Option<Event>> = fn(&mut T, Ctx, String, String) -> R;
+pub type AlertProcessorFn<T, R = Option<Event>> = fn(&mut T, Ctx, String) -> R;
+pub type ProcessorFn<T, R = Option<Event>> = fn(&mut T, Ctx, serde_json::Value) -> R;
+pub type LifecycleFn<T> = fn(&mut T, Ctx) -> Option<Event>;
+pub type TypedProcessorFn<T, E, R = Option<Event>> = fn(&mut T, Ctx, E) -> R;
+pub type RpcFn<T, R> = fn(&mut T, Ctx, R) -> Result<<R as Rpc>::Response, EventError>;
+type StateProcessor<T> =
+    Box<dyn Fn(&mut T, Ctx, String) -> Result<Option<Event>, EventError> + Send + Sync>;
+type CookieProcessor<T> =
+    Box<dyn Fn(&mut T, Ctx, String, String) -> Result<Option<Event>, EventError> + Send + Sync>;
+type AlertProcessor<T> =
+    Box<dyn Fn(&mut T, Ctx, String) -> Result<Option<Event>, EventError> + Send + Sync>;
+type Processor<T> =
+    Box<dyn Fn(&mut T, Ctx, serde_json::Value) -> Result<Option<Event>, EventError> + Send + Sync>;
+/// Renders a component by name with what its route matched, see [`App::ssr`].
+pub type SsrFn = fn(&str, &RouteParams) -> Option<String>;
+pub type AsyncProcessorFn<T> = Box<
+    dyn Fn(
+            StateHandle<T>,
+            Ctx,
+            serde_json::Value,
+        ) -> BoxFuture<'static, Result<Option<Event>, EventError>>
+        + Send
+        + Sync,
+>;
+
+#[derive(Debug)]
+struct QueuedEvent {
+    event: Event,
+    queued_at: Instant,
+}
+
+struct ApiState<T> {
+    events_tx: UnboundedSender<QueuedEvent>,
+    connected_clients: RwLock<HashMap<ConnectionId, ConnectedClient>>,
+    rooms: RwLock<HashMap<String, HashSet<ConnectionId>>>,
+    state_processor: RwLock<Option<StateProcessor<T>>>,
+    cookie_processor: RwLock<Option<CookieProcessor<T>>>,
+    alert_processor: RwLock<Option<AlertProcessor<T>>>,
+    processors: RwLock<Vec<Processor<T>>>,
+    async_processors: RwLock<Vec<AsyncProcessorFn<T>>>,
+    typed_processors: RwLock<HashMap<&'static str, Vec<TypedHandler<T>>>>,
+    rpc_handlers: RwLock<HashMap<&'static str, RpcHandler<T>>>,
+    on_connect: RwLock<Option<LifecycleFn<T>>>,
+    on_disconnect: RwLock<Option<LifecycleFn<T>>>,
+    on_session_start: RwLock<Option<LifecycleFn<T>>>,
+    on_session_end: RwLock<Option<LifecycleFn<T>>>,
+    routes: matchit::Router<String>,
+    ssr: Option<SsrFn>,
+    base_path: String,
+    index_html: String,
+    queue_capacity: usize,
+    slow_clients: SlowClientPolicy,
+    metrics: Metrics,
+    heartbeat: Heartbeat,
+    limits: Limits,
+    journal: Option<Journal>,
+    /// Async processors and rpc handlers still running, see [`ApiState::spawn`]
+    processing: Mutex<JoinSet<()>>,
+    /// Every socket along with its disconnect hooks, see `ws_handler`
+    connections: Mutex<JoinSet<()>>,
+    /// Flipped once the server starts shutting down
+    shutdown: watch::Sender<bool>,
     state: RwLock<T>,
 }
 
 impl<T: Send + Sync> ApiState<T> {
-    fn new(
-        state_processor: Option<Box<StateProcessorFn<T>>>,
-        cookie_processor: Option<Box<CookieProcessorFn<T>>>,
-        processors: Vec<ProcessorFn<T>>,
-        routes: HashMap<String, String>,
-        state: T,
-    ) -> Self {
+    /// Finds the component registered for a page along with what its pattern captured.
+    fn match_route(&self, path: &str, query: String) -> Option<(String, RouteParams)> {
+        let matched = self.routes.at(path).ok()?;
+        let path = matched
+            .params
+            .iter()
+            .map(|(name, value)| {
+                let value = percent_decode_str(value).decode_utf8_lossy();
+                (name.to_string(), value.into_owned())
+            })
+            .collect();
+
+        Some((matched.value.clone(), RouteParams { path, query }))
+    }
+
+    fn queue(&self, event: Event) {
+        // NOTE: the receiver only goes away once the dispatch task is gone, at which point
+        // there's nobody left to deliver the event to anyways
+        let _ = self.events_tx.send(QueuedEvent {
+            event,
+            queued_at: Instant::now(),
+        });
+    }
+
+    fn ctx(&self, who: SessionId, connection: ConnectionId) -> Ctx {
+        Ctx {
+            who,
+            connection,
+            events_tx: self.events_tx.clone(),
+        }
+    }
+
+    async fn run_lifecycle_hook(
+        &self,
+        hook: &RwLock<Option<LifecycleFn<T>>>,
+        who: SessionId,
+        connection: ConnectionId,
+    ) {
+        if let Some(hook) = hook.read().await.deref() {
+            let mut user_state = self.state.write().await;
+            if let Some(event) = hook(&mut user_state, self.ctx(who, connection)) {
+                self.queue(event);
+            }
+        }
+    }
+
+    /// Queues `event` for every connection matching `to`, dropping any that fell too far behind.
+    async fn deliver(
+        &self,
+        event: ToClientEvent,
+        to: impl Fn(ConnectionId, &ConnectedClient) -> bool,
+    ) {
+        let mut clients = self.connected_clients.write().await;
+        let mut clients_to_remove = Vec::new();
+
+        for (connection, client) in clients.iter().filter(|(id, client)| to(**id, client)) {
+            if client.queue.push(event.clone()).is_err() {
+                tracing::warn!(
+                    "client {} on connection {connection} can't keep up, disconnecting",
+                    client.session
+                );
+                clients_to_remove.push(*connection);
+            }
+        }
+
+        for connection in clients_to_remove {
+            clients.remove(&connection);
+        }
+    }
+
+    /// Logs `err` and sends it to the connection whose event caused it.
+    fn report_error(
+        &self,
+        from: SessionId,
+        connection: ConnectionId,
+        correlation: Option<&str>,
+        err: EventError,
+    ) {
+        tracing::warn!(%from, %connection, correlation, error = %err, "failed to process event");
+
+        self.queue(Event::ToConnection {
+            connection,
+            event: ToClientEvent::Error {
+                code: err.code,
+                message: err.message,
+                correlation: correlation.map(str::to_string),
+            },
+        });
+    }
+
+    /// Sends the outcome of a [`ToServerEvent::Request`] back to the connection that made it.
+    fn respond(
+        &self,
+        from: SessionId,
+        connection: ConnectionId,
+        id: u32,
+        result: Result<serde_json::Value, EventError>,
+    ) {
+        match result {
+            Ok(payload) => self.queue(Event::ToConnection {
+                connection,
+                event: ToClientEvent::Response { id, payload },
+            }),
+            Err(err) => self.report_error(from, connection, Some(&id.to_string()), err),
+        }
+    }
+
+    /// Runs `task` on its own, shutting down waits for it to finish, see `dispatch_events`.
+    fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let mut processing = self
+            .processing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // NOTE: finished tasks stay in the set until they're joined
+        while processing.try_join_next().is_some() {}
+        processing.spawn(task);
+    }
+
+    fn send_to_server(&self, from: SessionId, connection: ConnectionId, event: ToServerEvent) {
+        self.queue(Event::ToServer {
+            from,
+            connection,
+            event,
+        });
+    }
+}
+
+/// Shared access to the app state for processors that outlive a single dispatch.
+///
+/// The state lock is only taken when asked for, so I/O can be awaited without blocking
+/// every other processor.
+pub struct StateHandle<T> {
+    inner: Arc<ApiState<T>>,
+}
+
+impl<T> Clone for StateHandle<T> {
+    fn clone(&self) -> Self {
         Self {
-            events_to_be_sent: RwLock::new(VecDeque::new()),
-            connected_clients: RwLock::new(HashMap::new()),
-            state_processor: RwLock::new(state_processor),
-            cookie_processor: RwLock::new(cookie_processor),
-            processors: RwLock::new(processors),
-            routes: RwLock::new(routes),
-            state: RwLock::new(state),
+            inner: self.inner.clone(),
         }
     }
+}
 
-    async fn send_to_server(&self, from: SocketAddr, event: ToServerEvent) {
-        self.events_to_be_sent
-            .write()
-            .await
-            .push_back(Event::ToServer { from, event });
+impl<T: Send + Sync> StateHandle<T> {
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.inner.state.read().await
     }
 
-    async fn send_to_all_clients(&self, event: ToClientEvent) {
-        self.events_to_be_sent
-            .write()
-            .await
-            .push_back(Event::ToAllClients(event));
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.inner.state.write().await
     }
 
-    async fn send_to_specific_client(&self, who: SocketAddr, event: ToClientEvent) {
-        self.events_to_be_sent
-            .write()
-            .await
-            .push_back(Event::ToSpecificClient { who, event });
+    /// Takes the write lock just long enough to run `f`.
+    pub async fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut *self.inner.state.write().await)
+    }
+}
+
+/// Handed to every processor: who sent the event, and a way to queue up as many events as
+/// the processor needs on top of the one it returns.
+#[derive(Debug, Clone)]
+pub struct Ctx {
+    who: SessionId,
+    connection: ConnectionId,
+    events_tx: UnboundedSender<QueuedEvent>,
+}
+
+impl Ctx {
+    /// The browser the event came from, never the secret in its session cookie.
+    pub fn who(&self) -> SessionId {
+        self.who
+    }
+
+    /// The websocket (i.e. browser tab) the event came in on.
+    pub fn connection(&self) -> ConnectionId {
+        self.connection
+    }
+
+    pub fn send(&self, event: Event) {
+        let _ = self.events_tx.send(QueuedEvent {
+            event,
+            queued_at: Instant::now(),
+        });
+    }
+
+    /// Sends an event back to the connection the one being processed came in on.
+    pub fn reply(&self, event: ToClientEvent) {
+        self.send(Event::ToConnection {
+            connection: self.connection,
+            event,
+        });
+    }
+
+    pub fn broadcast(&self, event: ToClientEvent) {
+        self.send(Event::ToAllClients(event));
+    }
+
+    /// Adds the connection the event came in on to `room`, see [`Event::ToRoom`].
+    pub fn join(&self, room: impl Into<String>) {
+        self.send(Event::JoinRoom {
+            room: room.into(),
+            connection: self.connection,
+        });
+    }
+
+    pub fn leave(&self, room: impl Into<String>) {
+        self.send(Event::LeaveRoom {
+            room: room.into(),
+            connection: self.connection,
+        });
+    }
+
+    pub fn to_room(&self, room: impl Into<String>, event: ToClientEvent) {
+        self.send(Event::ToRoom {
+            room: room.into(),
+            event,
+        });
     }
 }
 
 struct ConnectedClient {
-    who: SocketAddr,
-    tx: Sender<ToClientEvent>,
+    session: SessionId,
+    queue: Arc<ClientQueue>,
     // rx: Receiver<Event>,
 }
 
 #[derive(Debug)]
 pub enum Event {
     ToServer {
-        from: SocketAddr,
+        from: SessionId,
+        connection: ConnectionId,
         event: ToServerEvent,
     },
     ToAllClients(ToClientEvent),
+    /// Sent to every connection belonging to the session
     ToSpecificClient {
-        who: SocketAddr,
+        who: SessionId,
+        event: ToClientEvent,
+    },
+    ToConnection {
+        connection: ConnectionId,
+        event: ToClientEvent,
+    },
+    /// Sent to every connection that has joined the room
+    ToRoom {
+        room: String,
         event: ToClientEvent,
     },
+    /// Connections leave all of their rooms on their own once they close
+    JoinRoom {
+        room: String,
+        connection: ConnectionId,
+    },
+    LeaveRoom {
+        room: String,
+        connection: ConnectionId,
+    },
 }
 
 #[derive(Debug, Clone)]
 pub struct UserContext {}
 
-#[derive(Debug, Deserialize)]
-#[serde(tag = "type", rename_all = "camelCase")]
-pub enum ToServerEvent {
-    Test(String),
-    PageLoad { path: String, params: String },
-    RequestFullState { name: String },
-    Cookie { name: String, value: String },
-    Custom(serde_json::Value),
-}
+const DEFAULT_BIND_ADDR: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED), 3000);
 
-#[derive(Debug, Clone, Serialize)]
-#[serde(tag = "type", rename_all = "camelCase")]
-pub enum ToClientEvent {
-    Alert {
-        msg: String,
-    },
+const DEFAULT_QUEUE_CAPACITY: usize = 100;
 
-    #[serde(rename_all = "camelCase")]
-    DomUpdate {
-        dom_id: String,
-        html: String,
-    },
+/// How long clients get to receive what's left for them before they're disconnected.
+const CLOSE_GRACE_PERIOD: Duration = Duration::from_secs(5);
 
-    #[serde(rename_all = "camelCase")]
-    RenderComponent {
-        component_name: String,
-        params: Option<String>,
-        dom_id: Option<String>,
-    },
+/// See [`App::heartbeat`].
+#[derive(Debug, Clone, Copy)]
+struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+}
 
-    Custom {
-        event: serde_json::Value,
-    },
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(45),
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct App<T: Default> {
-    state_processor: Option<Box<StateProcessorFn<T>>>,
-    cookie_processor: Option<Box<CookieProcessorFn<T>>>,
-    processors: Vec<ProcessorFn<T>>,
+    state_processor: Option<StateProcessor<T>>,
+    cookie_processor: Option<CookieProcessor<T>>,
+    alert_processor: Option<AlertProcessor<T>>,
+    processors: Vec<Processor<T>>,
+    async_processors: Vec<AsyncProcessorFn<T>>,
+    typed_processors: HashMap<&'static str, Vec<TypedHandler<T>>>,
+    /// Events passed to [`App::on`] that can't be routed by tag, refused when serving
+    untagged_events: Vec<&'static str>,
+    rpc_handlers: HashMap<&'static str, RpcHandler<T>>,
+    on_connect: Option<LifecycleFn<T>>,
+    on_disconnect: Option<LifecycleFn<T>>,
+    on_session_start: Option<LifecycleFn<T>>,
+    on_session_end: Option<LifecycleFn<T>>,
     routes: HashMap<String, String>,
+    ssr: Option<SsrFn>,
+    shell: Shell,
+    wire_format: WireFormat,
+    queue_capacity: Option<usize>,
+    slow_clients: SlowClientPolicy,
+    metrics: Metrics,
+    heartbeat: Heartbeat,
+    limits: Limits,
+    persistence: Option<Persistence<T>>,
+    migrations: Migrations,
+    journal: Option<PathBuf>,
+    assets: Vec<(String, Assets)>,
     wasm: Option<&'static [u8]>,
+    bind_addr: Option<SocketAddr>,
+    listener: Option<tokio::net::TcpListener>,
+    base_path: String,
     state: T,
+    /// Hands the running server over to tests, see `tests::serve`
+    #[cfg(test)]
+    serving: Option<tokio::sync::oneshot::Sender<Arc<ApiState<T>>>>,
 }
 
 impl<T: Default + Send + Sync + 'static> App<T> {
-    pub fn state_processor(mut self, f: StateProcessorFn<T>) -> Self {
-        self.state_processor = Some(Box::new(f));
+    /// Answers a client asking for the full value of a [`Stateful`](crate::state::Stateful) by
+    /// name, returning an [`EventError`] (e.g. for names it doesn't know) tells the client.
+    pub fn state_processor<R: ProcessorOutput + 'static>(
+        mut self,
+        f: StateProcessorFn<T, R>,
+    ) -> Self {
+        self.state_processor = Some(Box::new(move |state, ctx, name| {
+            f(state, ctx, name).into_result()
+        }));
         self
     }
 
-    pub fn cookie_processor(mut self, f: CookieProcessorFn<T>) -> Self {
-        self.cookie_processor = Some(Box::new(f));
+    pub fn cookie_processor<R: ProcessorOutput + 'static>(
+        mut self,
+        f: CookieProcessorFn<T, R>,
+    ) -> Self {
+        self.cookie_processor = Some(Box::new(move |state, ctx, name, value| {
+            f(state, ctx, name, value).into_result()
+        }));
+        self
+    }
+
+    /// Decides what happens to an alert a client sent, e.g. checking the sender is allowed to
+    /// and then sending a [`ToClientEvent::Alert`] to everyone. Alerts are rejected without one.
+    ///
+    /// ```ignore
+    /// fn alert(state: &mut State, ctx: Ctx, msg: String) -> Result<Option<Event>, EventError> {
+    ///     if !state.admins.contains(&ctx.who()) {
+    ///         return Err(EventError::new("forbidden", "only admins can alert everyone"));
+    ///     }
+    ///
+    ///     Ok(Some(Event::ToAllClients(ToClientEvent::Alert { msg })))
+    /// }
+    /// ```
+    pub fn alert_processor<R: ProcessorOutput + 'static>(
+        mut self,
+        f: AlertProcessorFn<T, R>,
+    ) -> Self {
+        self.alert_processor = Some(Box::new(move |state, ctx, msg| {
+            f(state, ctx, msg).into_result()
+        }));
         self
     }
 
-    pub fn add_processor(mut self, f: ProcessorFn<T>) -> Self {
-        self.processors.push(f);
+    /// Sees every custom event, whatever its tag. Returning an [`EventError`] tells the client
+    /// that sent it, see [`App::on`].
+    pub fn add_processor<R: ProcessorOutput + 'static>(mut self, f: ProcessorFn<T, R>) -> Self {
+        self.processors.push(Box::new(move |state, ctx, value| {
+            f(state, ctx, value).into_result()
+        }));
         self
     }
 
+    /// Like [`App::add_processor`], but the processor runs on its own task and is free to
+    /// await I/O. Use the [`StateHandle`] to lock the state only when it needs to be touched.
+    pub fn add_async_processor<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(StateHandle<T>, Ctx, serde_json::Value) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: ProcessorOutput,
+    {
+        self.async_processors
+            .push(Box::new(move |state, who, value| {
+                Box::pin(f(state, who, value).map(ProcessorOutput::into_result))
+            }));
+        self
+    }
+
+    /// Registers a processor for a single kind of custom event.
+    ///
+    /// `E` is either an externally tagged enum, which receives every one of its variants, or a
+    /// struct, which receives the client event variant with the same name. Events with any
+    /// other tag never reach `f`, and payloads that don't deserialize are reported instead of
+    /// handed over. Any other `E`, e.g. an internally tagged enum, is refused with a
+    /// [`ConfigError::UntaggedEvent`] once the app is served.
+    ///
+    /// `f` can return a `Result`, its [`EventError`] is sent to the connection the event came
+    /// from as a [`ToClientEvent::Error`].
+    pub fn on<E, R>(mut self, f: TypedProcessorFn<T, E, R>) -> Self
+    where
+        E: DeserializeOwned + 'static,
+        R: ProcessorOutput + 'static,
+    {
+        let Some(tags) = EventTags::of::<E>() else {
+            self.untagged_events.push(std::any::type_name::<E>());
+            return self;
+        };
+
+        for tag in tags.tags() {
+            self.typed_processors
+                .entry(tag)
+                .or_default()
+                .push(TypedHandler::Sync(Box::new(move |state, ctx, value| {
+                    let event = tags.deserialize::<E>(tag, value)?;
+                    f(state, ctx, event).into_result()
+                })));
+        }
+        self
+    }
+
+    /// The async flavor of [`App::on`], see [`App::add_async_processor`].
+    pub fn on_async<E, F, Fut>(mut self, f: F) -> Self
+    where
+        E: DeserializeOwned + 'static,
+        F: Fn(StateHandle<T>, Ctx, E) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: ProcessorOutput,
+    {
+        let Some(tags) = EventTags::of::<E>() else {
+            self.untagged_events.push(std::any::type_name::<E>());
+            return self;
+        };
+        let f = Arc::new(f);
+
+        for tag in tags.tags() {
+            let f = f.clone();
+            self.typed_processors
+                .entry(tag)
+                .or_default()
+                .push(TypedHandler::Async(Box::new(move |state, ctx, value| {
+                    let event = tags.deserialize::<E>(tag, value)?;
+                    Ok(Box::pin(
+                        f(state, ctx, event).map(ProcessorOutput::into_result),
+                    ))
+                })));
+        }
+        self
+    }
+
+    /// Answers every [`Rpc`] request of type `R`, the response (or the [`EventError`]) only goes
+    /// back to the connection that asked.
+    ///
+    /// Registering another handler for the same [`Rpc::method`] replaces this one.
+    pub fn rpc<R: Rpc + 'static>(mut self, f: RpcFn<T, R>) -> Self {
+        self.rpc_handlers.insert(
+            R::method(),
+            RpcHandler::Sync(Box::new(move |state, ctx, payload| {
+                let request = rpc::decode_request::<R>(payload)?;
+                rpc::encode_response::<R>(f(state, ctx, request)?)
+            })),
+        );
+        self
+    }
+
+    /// The async flavor of [`App::rpc`], see [`App::add_async_processor`].
+    pub fn rpc_async<R, F, Fut>(mut self, f: F) -> Self
+    where
+        R: Rpc + 'static,
+        F: Fn(StateHandle<T>, Ctx, R) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R::Response, EventError>> + Send + 'static,
+    {
+        self.rpc_handlers.insert(
+            R::method(),
+            RpcHandler::Async(Box::new(move |state, ctx, payload| {
+                let request = rpc::decode_request::<R>(payload)?;
+                Ok(Box::pin(
+                    f(state, ctx, request).map(|response| rpc::encode_response::<R>(response?)),
+                ))
+            })),
+        );
+        self
+    }
+
+    /// Runs for every websocket once it's up, before any of its events are processed.
+    /// [`Ctx::connection`] is the new websocket.
+    pub fn on_connect(mut self, f: LifecycleFn<T>) -> Self {
+        self.on_connect = Some(f);
+        self
+    }
+
+    /// Runs for every websocket once it's gone and has been removed from the connected clients,
+    /// with the same [`Ctx::connection`] [`App::on_connect`] saw. This is the place to clean up
+    /// any per-connection state.
+    pub fn on_disconnect(mut self, f: LifecycleFn<T>) -> Self {
+        self.on_disconnect = Some(f);
+        self
+    }
+
+    /// Runs when a session opens its first websocket, before [`App::on_connect`] runs for it.
+    /// Other tabs of the same browser join the session without running this again.
+    pub fn on_session_start(mut self, f: LifecycleFn<T>) -> Self {
+        self.on_session_start = Some(f);
+        self
+    }
+
+    /// Runs once a session's last websocket is gone, after [`App::on_disconnect`] ran for it.
+    /// This is the place to clean up state keyed by [`Ctx::who`].
+    pub fn on_session_end(mut self, f: LifecycleFn<T>) -> Self {
+        self.on_session_end = Some(f);
+        self
+    }
+
+    /// Serves `component_name` on every path matching `path`.
+    ///
+    /// Patterns use axum's syntax, `/user/{id}` for a named segment and `/files/{*rest}` for a
+    /// wildcard. What they capture reaches the component as [`RouteParams`].
     pub fn route(mut self, path: &str, component_name: &str) -> Self {
         self.routes
             .insert(path.to_string(), component_name.to_string());
         self
     }
 
+    /// Renders routes on the server so the page has content before the wasm blob loads.
+    ///
+    /// `f` is the client's component renderer compiled for the server, it gets the route's
+    /// component name and the parameters it matched. The client adopts the rendered nodes once it
+    /// starts instead of rendering them again.
+    pub fn ssr(mut self, f: SsrFn) -> Self {
+        self.ssr = Some(f);
+        self
+    }
+
+    /// The html page routes are served in, see [`Shell`] for what a template can contain.
+    pub fn shell(mut self, shell: Shell) -> Self {
+        self.shell = shell;
+        self
+    }
+
+    /// The encoding the wasm client asks for when it connects, JSON by default.
+    ///
+    /// [`WireFormat::MessagePack`] is smaller and quicker for the wasm client to decode, which
+    /// adds up for big state updates. Every connection gets the format it asked for, so other
+    /// clients can pick their own, see [`WireFormat::negotiate`].
+    pub fn wire_format(mut self, format: WireFormat) -> Self {
+        self.wire_format = format;
+        self
+    }
+
+    /// How many events can wait for a client before `policy` decides what happens, 100 by
+    /// default.
+    ///
+    /// Events wait when a client reads slower than they're sent, e.g. on a bad connection.
+    pub fn slow_clients(mut self, capacity: usize, policy: SlowClientPolicy) -> Self {
+        self.queue_capacity = Some(capacity);
+        self.slow_clients = policy;
+        self
+    }
+
+    /// How often clients are sent a heartbeat, and how long one can go without answering before
+    /// it's disconnected. Every 15 seconds with a 45 second timeout by default.
+    ///
+    /// Half-open connections (e.g. a laptop that went to sleep) never close on their own,
+    /// this is what eventually cleans them up and runs [`App::on_disconnect`]. The browser
+    /// shows the connection as stale once heartbeats stop arriving.
+    ///
+    /// `timeout` has to be longer than `interval`, otherwise clients could be disconnected
+    /// before they were even asked to answer.
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat = Heartbeat { interval, timeout };
+        self
+    }
+
+    /// How many messages a connection can send per second, with bursts of up to `burst`. 20 per
+    /// second with bursts of 50 by default.
+    ///
+    /// Messages over the limit are dropped and answered with [`ToClientEvent::Error`], a client
+    /// that keeps going anyways is disconnected.
+    pub fn rate_limit(mut self, per_second: u32, burst: u32) -> Self {
+        self.limits.per_second = per_second;
+        self.limits.burst = burst.max(1);
+        self
+    }
+
+    /// The biggest message a client can send, 64 KiB by default. Handled like
+    /// [`App::rate_limit`].
+    ///
+    /// Messages over 4 times the limit aren't read at all, the client is sent the same error
+    /// and then disconnected, since the rest of the message is still on its way. Zero would
+    /// refuse every message, so it's refused with a [`ConfigError::ZeroFrameSize`].
+    pub fn max_frame_size(mut self, bytes: usize) -> Self {
+        self.limits.max_frame_size = bytes;
+        self
+    }
+
+    /// Appends every custom event that reaches the processors to the file at `path`, along with
+    /// who sent it and when. One [`JournalEntry`] per line, see [`App::replay`].
+    pub fn journal(mut self, path: impl Into<PathBuf>) -> Self {
+        self.journal = Some(path.into());
+        self
+    }
+
+    /// Rebuilds the state by running the journal at `path` through the processors, starting from
+    /// [`App::state`]. Nothing is served and whatever the processors send is thrown away.
+    ///
+    /// Only processors that don't await anything are run, async ones are free to do I/O that
+    /// can't be repeated. Errors they return were already sent to the client the first time
+    /// around, so they're skipped.
+    ///
+    /// ```ignore
+    /// let state = App::default()
+    ///     .on(add_meme)
+    ///     .replay("journal.jsonl")?;
+    /// assert_eq!(state.meme_list.len(), 5);
+    /// ```
+    pub fn replay(self, path: impl AsRef<std::path::Path>) -> Result<T, ReplayError> {
+        let entries = journal::read(path.as_ref())?;
+        let mut state = self.state;
+
+        // NOTE: kept around so processors can still send, nobody is listening though
+        let (events_tx, _events_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        for JournalEntry {
+            from,
+            connection,
+            event,
+            ..
+        } in entries
+        {
+            let ctx = Ctx {
+                who: from,
+                connection,
+                events_tx: events_tx.clone(),
+            };
+            let typed_handlers =
+                typed::event_tag(&event).and_then(|tag| self.typed_processors.get(tag));
+
+            process_sync(
+                &mut state,
+                &ctx,
+                &self.processors,
+                typed_handlers,
+                &event,
+                |_| {},
+            );
+        }
+
+        Ok(state)
+    }
+
+    /// Handle to the server's counters, can be read while it's running.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    /// Serves the files in `dir` under `prefix`, e.g. `/static/app.css` from `static/app.css`.
+    ///
+    /// Files are read from disk on every request and always revalidated, use
+    /// [`App::embedded_assets`] for release builds.
+    pub fn assets(mut self, prefix: &str, dir: impl Into<PathBuf>) -> Self {
+        self.assets
+            .push((normalize_prefix(prefix), Assets::Dir(dir.into())));
+        self
+    }
+
+    /// Like [`App::assets`], but serves files compiled into the binary, see
+    /// [`embed_assets!`](crate::embed_assets).
+    pub fn embedded_assets(mut self, prefix: &str, files: &[(&str, &'static [u8])]) -> Self {
+        self.assets
+            .push((normalize_prefix(prefix), Assets::embedded(files)));
+        self
+    }
+
     pub fn state(mut self, state: T) -> Self {
         self.state = state;
         self
     }
 
+    /// The client's wasm blob, compressed with brotli and gzip before the server accepts any
+    /// connections. For a debug build that can take a few seconds.
     pub fn wasm(mut self, blob: &'static [u8]) -> Self {
         self.wasm = Some(blob);
         self
     }
 
-    pub async fn serve(self) -> Result<(), Box<dyn std::error::Error>> {
-        let state = Arc::new(ApiState::new(
-            self.state_processor,
-            self.cookie_processor,
-            self.processors,
-            self.routes.clone(),
-            self.state,
-        ));
-
-        let cloned_state = state.clone();
-        tokio::spawn(async move {
-            let state = cloned_state;
-            loop {
-                let mut clients_to_remove =
-                    Vec::with_capacity(state.connected_clients.read().await.len());
-                let mut pending_events = Vec::new();
-
-                while let Some(event) = state.events_to_be_sent.write().await.pop_front() {
-                    match event {
-                        Event::ToServer { from, event } => {
-                            // TODO: send to wasm module endpoint
-                            tracing::info!("look at me i'm totally a real wasm module {event:?}");
-
-                            tracing::debug!("event: {event:?}");
-
-                            match event {
-                                ToServerEvent::Test(_) => {}
-                                ToServerEvent::RequestFullState { name } => {
-                                    tracing::info!("{from} is requesting full state {name}");
-                                    if let Some(state_processor) =
-                                        state.state_processor.read().await.deref()
-                                    {
-                                        let mut state = state.state.write().await;
-                                        if let Some(event) = state_processor(&mut state, from, name)
-                                        {
-                                            pending_events.push(event);
-                                        }
-                                    } else {
-                                        tracing::error!("no state processor registered");
-                                    }
-                                }
-                                ToServerEvent::Cookie { name, value } => {
-                                    if let Some(cookie_processor) =
-                                        state.cookie_processor.read().await.deref()
-                                    {
-                                        let mut state = state.state.write().await;
-                                        if let Some(event) =
-                                            cookie_processor(&mut state, from, name, value)
-                                        {
-                                            pending_events.push(event);
-                                        }
-                                    } else {
-                                        tracing::error!("no cookie processor registered");
-                                    }
-                                }
-                                ToServerEvent::PageLoad { path, params } => {
-                                    if let Some(component_name) =
-                                        state.routes.read().await.get(&path)
-                                    {
-                                        pending_events.push(Event::ToSpecificClient {
-                                            who: from,
-                                            event: ToClientEvent::RenderComponent {
-                                                component_name: component_name.clone(),
-                                                params: Some(params.clone()),
-                                                dom_id: Some("test".to_string()),
-                                            },
-                                        });
-                                    }
-                                }
-                                ToServerEvent::Custom(value) => {
-                                    let mut user_state = state.state.write().await;
-
-                                    for processor in state.processors.read().await.iter() {
-                                        // TODO: async?
-                                        if let Some(event) =
-                                            processor(&mut user_state, from, value.clone())
-                                        {
-                                            pending_events.push(event);
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        Event::ToAllClients(to_client_event) => {
-                            // tracing::debug!("sending ToAllClients event {to_client_event:?}");
-
-                            let mut clients = state.connected_clients.write().await;
-                            for (who, client) in clients.iter_mut() {
-                                if client.tx.send(to_client_event.clone()).await.is_err() {
-                                    tracing::error!(
-                                        "failed to send ToAllClients event to client {:?}",
-                                        client.who
-                                    );
-                                    clients_to_remove.push(*who);
-                                }
-                            }
-                        }
-                        Event::ToSpecificClient { who, event } => {
-                            if let Some(client) =
-                                state.connected_clients.write().await.get_mut(&who)
-                            {
-                                if client.tx.send(event.clone()).await.is_err() {
-                                    tracing::error!(
-                                        "failed to send ToAllClients event to client {:?}",
-                                        client.who
-                                    );
-                                    clients_to_remove.push(who);
-                                }
-                            }
-                        }
-                    }
-                }
+    /// Address to listen on, defaults to `0.0.0.0:3000`.
+    ///
+    /// Ignored if a listener was provided with [`App::listener`].
+    pub fn bind(mut self, addr: impl Into<SocketAddr>) -> Self {
+        self.bind_addr = Some(addr.into());
+        self
+    }
 
-                state
-                    .events_to_be_sent
-                    .write()
-                    .await
-                    .append(&mut pending_events.into());
+    /// Serve on an already bound listener (e.g. one bound to port 0 in tests).
+    pub fn listener(mut self, listener: tokio::net::TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Path prefix the app is mounted under, e.g. `/my-app` when sitting behind a reverse proxy.
+    ///
+    /// Routes, the wasm blob and `/ws` are all served relative to this prefix.
+    pub fn base_path(mut self, prefix: &str) -> Self {
+        self.base_path = normalize_prefix(prefix);
+        self
+    }
 
-                for who in clients_to_remove.into_iter().rev() {
-                    state.connected_clients.write().await.remove(&who);
+    pub async fn serve(self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.serve_with_shutdown(std::future::pending()).await?;
+
+        Ok(())
+    }
+
+    /// Like [`App::serve`], but shuts down once `signal` completes and returns the final state.
+    ///
+    /// New connections are refused, events that are already queued are still delivered, async
+    /// processors that are still running get to finish, and clients are sent a close frame
+    /// once they've received everything.
+    ///
+    /// ```ignore
+    /// let state = app
+    ///     .serve_with_shutdown(async { tokio::signal::ctrl_c().await.unwrap() })
+    ///     .await?;
+    /// ```
+    pub async fn serve_with_shutdown(
+        self,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+        let blob = self.wasm.ok_or(ConfigError::MissingWasm)?;
+        // NOTE: it ends up in the session cookie's `Path`, see `ws_handler`
+        if !self
+            .base_path
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && b != b';')
+        {
+            return Err(ConfigError::InvalidBasePath {
+                base_path: self.base_path,
+            }
+            .into());
+        }
+        if let Some(&event) = self.untagged_events.first() {
+            return Err(ConfigError::UntaggedEvent { event }.into());
+        }
+        if self.heartbeat.interval.is_zero() {
+            return Err(ConfigError::ZeroInterval {
+                setting: "App::heartbeat",
+            }
+            .into());
+        }
+        if self.limits.max_frame_size == 0 {
+            return Err(ConfigError::ZeroFrameSize.into());
+        }
+        if self.heartbeat.timeout <= self.heartbeat.interval {
+            return Err(ConfigError::HeartbeatTimeout {
+                interval: self.heartbeat.interval,
+                timeout: self.heartbeat.timeout,
+            }
+            .into());
+        }
+        let mut persistence = self.persistence;
+        match &mut persistence {
+            Some(persistence) => {
+                if persistence.interval.is_zero() {
+                    return Err(ConfigError::ZeroInterval {
+                        setting: "App::persist",
+                    }
+                    .into());
                 }
+                if let Some(from) = self.migrations.unreachable() {
+                    return Err(ConfigError::UnreachableMigration {
+                        from,
+                        version: self.migrations.version,
+                    }
+                    .into());
+                }
+                persistence.migrations = self.migrations;
+            }
+            None if !self.migrations.is_empty() => return Err(ConfigError::NotPersisted.into()),
+            None => {}
+        }
 
-                tokio::time::sleep(Duration::from_millis(50)).await;
+        // NOTE: checked before the axum router is built, which panics on the same patterns
+        let mut routes = matchit::Router::new();
+        for (path, component_name) in &self.routes {
+            let inserted = if path.starts_with('/') {
+                routes
+                    .insert(path, component_name.clone())
+                    .map_err(|err| err.to_string())
+            } else {
+                Err("routes must start with a `/`".to_string())
+            };
+            if let Err(err) = inserted {
+                return Err(ConfigError::InvalidRoute {
+                    path: path.clone(),
+                    err,
+                }
+                .into());
             }
+        }
+
+        let mut user_state = self.state;
+        if let Some(persistence) = &persistence
+            && let Some(state) = persistence.load().await?
+        {
+            tracing::info!("loaded state from {}", persistence.path.display());
+            user_state = state;
+        }
+
+        // NOTE: done before anything is accepted, so the blob is never served uncompressed
+        let wasm = Arc::new(tokio::task::spawn_blocking(move || WasmBlob::new(blob)).await?);
+
+        let journal = match self.journal {
+            Some(path) => Some(Journal::open(path).await?),
+            None => None,
+        };
+
+        let (events_tx, events_rx) = tokio::sync::mpsc::unbounded_channel();
+        let state = Arc::new(ApiState {
+            events_tx,
+            connected_clients: RwLock::new(HashMap::new()),
+            rooms: RwLock::new(HashMap::new()),
+            state_processor: RwLock::new(self.state_processor),
+            cookie_processor: RwLock::new(self.cookie_processor),
+            alert_processor: RwLock::new(self.alert_processor),
+            processors: RwLock::new(self.processors),
+            async_processors: RwLock::new(self.async_processors),
+            typed_processors: RwLock::new(self.typed_processors),
+            rpc_handlers: RwLock::new(self.rpc_handlers),
+            on_connect: RwLock::new(self.on_connect),
+            on_disconnect: RwLock::new(self.on_disconnect),
+            on_session_start: RwLock::new(self.on_session_start),
+            on_session_end: RwLock::new(self.on_session_end),
+            routes,
+            ssr: self.ssr,
+            base_path: self.base_path.clone(),
+            index_html: self.shell.render(
+                &self.base_path,
+                &format!("{}{}", self.base_path, wasm.hashed_path()),
+                self.wire_format,
+                self.heartbeat,
+            ),
+            queue_capacity: self.queue_capacity.unwrap_or(DEFAULT_QUEUE_CAPACITY),
+            slow_clients: self.slow_clients,
+            metrics: self.metrics,
+            heartbeat: self.heartbeat,
+            limits: self.limits,
+            journal,
+            processing: Mutex::new(JoinSet::new()),
+            connections: Mutex::new(JoinSet::new()),
+            shutdown: watch::Sender::new(false),
+            state: RwLock::new(user_state),
         });
 
+        #[cfg(test)]
+        if let Some(serving) = self.serving {
+            let _ = serving.send(state.clone());
+        }
+
+        let mut dispatch = tokio::spawn(dispatch_events(state.clone(), events_rx));
+        let persistence = persistence.map(Arc::new);
+        let persisting = persistence
+            .clone()
+            .map(|persistence| tokio::spawn(persist_state(state.clone(), persistence)));
+
         let mut component_routes = Router::new();
-        for (path, _) in self.routes {
-            component_routes = component_routes.route(&path, get(index));
+        for path in self.routes.keys() {
+            component_routes = component_routes.route(path, get(index));
         }
 
-        let app = Router::new()
+        let mut app = Router::new()
             // .route("/", get(index))
             .route(
                 "/client.wasm",
-                get(move || async move {
-                    Wasm(Bytes::from(self.wasm.expect("wasm blob not provided")))
+                get({
+                    let wasm = wasm.clone();
+                    move |headers: HeaderMap, if_none_match: Option<TypedHeader<headers::IfNoneMatch>>| async move {
+                        wasm.serve(&headers, if_none_match.map(|TypedHeader(header)| header), false)
+                    }
+                }),
+            )
+            .route(
+                &wasm.hashed_path(),
+                get({
+                    let wasm = wasm.clone();
+                    move |headers: HeaderMap, if_none_match: Option<TypedHeader<headers::IfNoneMatch>>| async move {
+                        wasm.serve(&headers, if_none_match.map(|TypedHeader(header)| header), true)
+                    }
                 }),
             )
             .route("/ws", get(ws_handler))
-            .merge(component_routes)
+            .merge(component_routes);
+
+        let started_at = SystemTime::now();
+        for (prefix, assets) in self.assets {
+            let assets = Arc::new(assets);
+
+            app = app.route(
+                &format!("{prefix}/{{*path}}"),
+                get(
+                    move |Path(path): Path<String>,
+                          if_none_match: Option<TypedHeader<headers::IfNoneMatch>>,
+                          if_modified_since: Option<TypedHeader<headers::IfModifiedSince>>| async move {
+                        assets
+                            .serve(
+                                &path,
+                                started_at,
+                                if_none_match.map(|TypedHeader(header)| header),
+                                if_modified_since.map(|TypedHeader(header)| header),
+                            )
+                            .await
+                    },
+                ),
+            );
+        }
+
+        if !self.base_path.is_empty() {
+            app = Router::new().nest(&self.base_path, app);
+        }
+
+        let app = app
             .layer(
                 TraceLayer::new_for_http()
                     .make_span_with(DefaultMakeSpan::default().include_headers(true)),
             )
             .with_state(state.clone());
 
-        let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
-        tracing::debug!("listening on {}", listener.local_addr()?);
+        let listener = match self.listener {
+            Some(listener) => listener,
+            None => {
+                tokio::net::TcpListener::bind(self.bind_addr.unwrap_or(DEFAULT_BIND_ADDR)).await?
+            }
+        };
+        tracing::debug!("listening on {}{}", listener.local_addr()?, self.base_path);
 
         axum::serve(
             listener,
             app.into_make_service_with_connect_info::<SocketAddr>(),
         )
+        .with_graceful_shutdown(signal)
         .await?;
 
-        Ok(())
+        tracing::info!("shutting down");
+        state.shutdown.send_replace(true);
+        // NOTE: async processors get as long as clients do, whatever's still running after that
+        // is cancelled so it can't touch the state once it's been handed back
+        if tokio::time::timeout(CLOSE_GRACE_PERIOD, &mut dispatch)
+            .await
+            .is_err()
+        {
+            tracing::warn!("gave up waiting for async processors to finish");
+            dispatch.abort();
+            state
+                .processing
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .abort_all();
+        }
+
+        for client in state.connected_clients.read().await.values() {
+            client.queue.drain(CloseFrame {
+                code: close_code::AWAY,
+                reason: "server is shutting down".into(),
+            });
+        }
+        // NOTE: the disconnect hooks still change the state, so it's only saved once they're done
+        let mut connections = std::mem::take(
+            &mut *state
+                .connections
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        if tokio::time::timeout(CLOSE_GRACE_PERIOD, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await
+        .is_err()
+        {
+            tracing::warn!("gave up waiting for clients to disconnect");
+            connections.shutdown().await;
+        }
+
+        if let (Some(persistence), Some(persisting)) = (persistence, persisting) {
+            // NOTE: it stops on its own once shutting down, but might be halfway through a save
+            let _ = persisting.await;
+            save_snapshot(&state, &persistence).await?;
+        }
+
+        Ok(std::mem::take(&mut *state.state.write().await))
+    }
+}
+
+impl<T: Default + Serialize + DeserializeOwned + Send + Sync + 'static> App<T> {
+    /// Saves the state to `path` every `interval` and once more after shutting down, see
+    /// [`App::serve_with_shutdown`]. The latest snapshot is loaded on startup in place of
+    /// [`App::state`], the server refuses to start if it can't be read.
+    pub fn persist(mut self, path: impl Into<PathBuf>, interval: Duration) -> Self {
+        self.persistence = Some(Persistence::new(path.into(), interval));
+        self
+    }
+
+    /// The version of `T`'s format snapshots are saved as, 0 by default. Bump it whenever `T`
+    /// changes in a way old snapshots can't be read as, and add an [`App::migration`] from the
+    /// previous version.
+    pub fn snapshot_version(mut self, version: u32) -> Self {
+        self.migrations.version = version;
+        self
+    }
+
+    /// Turns a snapshot saved as version `from` into one of version `from + 1`. Older snapshots
+    /// go through every migration up to [`App::snapshot_version`] when they're loaded, and
+    /// fail to load if one is missing.
+    ///
+    /// ```ignore
+    /// app.persist("snapshot.json", Duration::from_secs(30))
+    ///     .snapshot_version(2)
+    ///     .migration(0, |mut state| {
+    ///         state["meme_list"] = serde_json::json!([]);
+    ///         Ok(state)
+    ///     })
+    ///     .migration(1, |mut state| {
+    ///         state["check_boxes"] = serde_json::json!([]);
+    ///         Ok(state)
+    ///     })
+    /// ```
+    pub fn migration(mut self, from: u32, migrate: MigrateFn) -> Self {
+        self.migrations.add(from, migrate);
+        self
+    }
+}
+
+/// `/prefix` or empty, no matter how many slashes `prefix` had.
+fn normalize_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim_matches('/');
+
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("/{prefix}")
+    }
+}
+
+async fn dispatch_events<T: Send + Sync + 'static>(
+    state: Arc<ApiState<T>>,
+    mut events_rx: UnboundedReceiver<QueuedEvent>,
+) {
+    let mut shutdown = state.shutdown.subscribe();
+
+    loop {
+        let queued = tokio::select! {
+            queued = events_rx.recv() => queued,
+            () = shutting_down(&mut shutdown) => None,
+        };
+        let Some(QueuedEvent { event, queued_at }) = queued else {
+            break;
+        };
+        tracing::debug!(latency = ?queued_at.elapsed(), "dispatching event");
+
+        dispatch_event(&state, event).await;
+    }
+
+    // NOTE: sockets stop reading once shutting down, so this only has to catch up on what's
+    // already queued, whatever that queues in turn, and whatever async processors that are
+    // still running queue once they're done
+    loop {
+        while let Ok(QueuedEvent { event, .. }) = events_rx.try_recv() {
+            dispatch_event(&state, event).await;
+        }
+
+        let mut processing = std::mem::take(
+            &mut *state
+                .processing
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        if processing.is_empty() {
+            break;
+        }
+        while processing.join_next().await.is_some() {}
+    }
+}
+
+async fn dispatch_event<T: Send + Sync + 'static>(state: &Arc<ApiState<T>>, event: Event) {
+    match event {
+        Event::ToServer {
+            from,
+            connection,
+            event,
+        } => {
+            // TODO: send to wasm module endpoint
+            tracing::info!("look at me i'm totally a real wasm module {event:?}");
+
+            tracing::debug!("event: {event:?}");
+
+            match event {
+                // NOTE: heartbeats are answered before they're ever queued, see `process_message`
+                ToServerEvent::Test(_) | ToServerEvent::Heartbeat => {}
+                ToServerEvent::RequestFullState { name } => {
+                    tracing::info!("{from} is requesting full state {name}");
+                    let result = match state.state_processor.read().await.deref() {
+                        Some(state_processor) => {
+                            let mut user_state = state.state.write().await;
+                            state_processor(
+                                &mut user_state,
+                                state.ctx(from, connection),
+                                name.clone(),
+                            )
+                        }
+                        None => Err(EventError::new(
+                            "unknown_state",
+                            "no state processor registered",
+                        )),
+                    };
+
+                    match result {
+                        Ok(Some(event)) => state.queue(event),
+                        Ok(None) => {}
+                        Err(err) => state.report_error(from, connection, Some(&name), err),
+                    }
+                }
+                ToServerEvent::Cookie { name, value } => {
+                    let result = match state.cookie_processor.read().await.deref() {
+                        Some(cookie_processor) => {
+                            let mut user_state = state.state.write().await;
+                            cookie_processor(
+                                &mut user_state,
+                                state.ctx(from, connection),
+                                name.clone(),
+                                value,
+                            )
+                        }
+                        None => Err(EventError::new(
+                            "unknown_cookie",
+                            "no cookie processor registered",
+                        )),
+                    };
+
+                    match result {
+                        Ok(Some(event)) => state.queue(event),
+                        Ok(None) => {}
+                        Err(err) => state.report_error(from, connection, Some(&name), err),
+                    }
+                }
+                ToServerEvent::Request {
+                    id,
+                    method,
+                    payload,
+                } => match state.rpc_handlers.read().await.get(method.as_str()) {
+                    Some(RpcHandler::Sync(handler)) => {
+                        let mut user_state = state.state.write().await;
+                        let result = handler(&mut user_state, state.ctx(from, connection), payload);
+                        state.respond(from, connection, id, result);
+                    }
+                    Some(RpcHandler::Async(handler)) => {
+                        let handle = StateHandle {
+                            inner: state.clone(),
+                        };
+                        match handler(handle, state.ctx(from, connection), payload) {
+                            Ok(processing) => {
+                                let responding = state.clone();
+                                state.spawn(async move {
+                                    responding.respond(from, connection, id, processing.await);
+                                });
+                            }
+                            Err(err) => state.respond(from, connection, id, Err(err)),
+                        }
+                    }
+                    None => state.respond(from, connection, id, Err(rpc::unknown_method(&method))),
+                },
+                ToServerEvent::Alert { msg } => {
+                    let result = match state.alert_processor.read().await.deref() {
+                        Some(alert_processor) => {
+                            let mut user_state = state.state.write().await;
+                            alert_processor(&mut user_state, state.ctx(from, connection), msg)
+                        }
+                        None => Err(EventError::new(
+                            "unknown_alert",
+                            "no alert processor registered",
+                        )),
+                    };
+
+                    match result {
+                        Ok(Some(event)) => state.queue(event),
+                        Ok(None) => {}
+                        Err(err) => state.report_error(from, connection, None, err),
+                    }
+                }
+                ToServerEvent::PageLoad { path, params } => {
+                    if let Some((component_name, params)) = state.match_route(&path, params) {
+                        state.queue(Event::ToConnection {
+                            connection,
+                            event: ToClientEvent::RenderComponent {
+                                component_name,
+                                params: Some(params),
+                                dom_id: Some("test".to_string()),
+                            },
+                        });
+                    }
+                }
+                ToServerEvent::Custom(value) => {
+                    let typed_processors = state.typed_processors.read().await;
+                    let tag = typed::event_tag(&value);
+                    let typed_handlers = tag.and_then(|tag| typed_processors.get(tag));
+                    let processors = state.processors.read().await;
+                    let async_processors = state.async_processors.read().await;
+
+                    let handled = typed_handlers.is_some()
+                        || !processors.is_empty()
+                        || !async_processors.is_empty();
+                    if let (true, Some(journal)) = (handled, &state.journal) {
+                        journal.append(from, connection, &value).await;
+                    }
+
+                    process_sync(
+                        &mut *state.state.write().await,
+                        &state.ctx(from, connection),
+                        &processors,
+                        typed_handlers,
+                        &value,
+                        |result| match result {
+                            Ok(Some(event)) => state.queue(event),
+                            Ok(None) => {}
+                            Err(err) => state.report_error(from, connection, tag, err),
+                        },
+                    );
+
+                    for processor in async_processors.iter() {
+                        let handle = StateHandle {
+                            inner: state.clone(),
+                        };
+                        spawn_processing(
+                            state,
+                            processor(handle, state.ctx(from, connection), value.clone()),
+                            from,
+                            connection,
+                            tag,
+                        );
+                    }
+
+                    for handler in typed_handlers.into_iter().flatten() {
+                        let TypedHandler::Async(handler) = handler else {
+                            continue;
+                        };
+
+                        let handle = StateHandle {
+                            inner: state.clone(),
+                        };
+                        match handler(handle, state.ctx(from, connection), value.clone()) {
+                            Ok(processing) => {
+                                spawn_processing(state, processing, from, connection, tag)
+                            }
+                            Err(err) => state.report_error(from, connection, tag, err),
+                        }
+                    }
+
+                    if !handled {
+                        state.report_error(
+                            from,
+                            connection,
+                            tag,
+                            DispatchError::UnknownEvent {
+                                tag: tag.unwrap_or_default().to_string(),
+                            }
+                            .into(),
+                        );
+                    }
+                }
+            }
+        }
+        Event::ToAllClients(event) => {
+            state.deliver(event, |_, _| true).await;
+        }
+        Event::ToSpecificClient { who, event } => {
+            state
+                .deliver(event, |_, client| client.session == who)
+                .await;
+        }
+        Event::ToConnection { connection, event } => {
+            state.deliver(event, |id, _| id == connection).await;
+        }
+        Event::ToRoom { room, event } => {
+            let Some(members) = state.rooms.read().await.get(&room).cloned() else {
+                return;
+            };
+
+            state.deliver(event, |id, _| members.contains(&id)).await;
+        }
+        Event::JoinRoom { room, connection } => {
+            // NOTE: the connection might've closed while this was queued, don't resurrect it.
+            // Holding on to the clients until it's in the room means it can't close in between
+            // either, since closing takes the clients before the rooms too, see `handle_socket`
+            let clients = state.connected_clients.read().await;
+
+            if clients.contains_key(&connection) {
+                state
+                    .rooms
+                    .write()
+                    .await
+                    .entry(room)
+                    .or_default()
+                    .insert(connection);
+            }
+        }
+        Event::LeaveRoom { room, connection } => {
+            let mut rooms = state.rooms.write().await;
+            if let Some(members) = rooms.get_mut(&room) {
+                members.remove(&connection);
+
+                if members.is_empty() {
+                    rooms.remove(&room);
+                }
+            }
+        }
+    }
+}
+
+/// Runs the processors for a custom event that don't need to await anything, handing what each
+/// of them returned to `processed`.
+///
+/// Every [`App::add_processor`] runs before any typed processor from [`App::on`], each in the
+/// order they were registered.
+fn process_sync<T>(
+    user_state: &mut T,
+    ctx: &Ctx,
+    processors: &[Processor<T>],
+    typed_handlers: Option<&Vec<TypedHandler<T>>>,
+    value: &serde_json::Value,
+    mut processed: impl FnMut(Result<Option<Event>, EventError>),
+) {
+    for processor in processors {
+        processed(processor(user_state, ctx.clone(), value.clone()));
+    }
+
+    for handler in typed_handlers.into_iter().flatten() {
+        if let TypedHandler::Sync(handler) = handler {
+            processed(handler(user_state, ctx.clone(), value.clone()));
+        }
+    }
+}
+
+/// Saves a snapshot every [`Persistence::interval`] until the server starts shutting down.
+async fn persist_state<T: Send + Sync>(state: Arc<ApiState<T>>, persistence: Arc<Persistence<T>>) {
+    let mut shutdown = state.shutdown.subscribe();
+    let mut interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + persistence.interval,
+        persistence.interval,
+    );
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            () = shutting_down(&mut shutdown) => break,
+        }
+
+        if let Err(err) = save_snapshot(&state, &persistence).await {
+            tracing::warn!(
+                "failed to save snapshot to {}: {err}",
+                persistence.path.display()
+            );
+        }
+    }
+}
+
+async fn save_snapshot<T: Send + Sync>(
+    state: &ApiState<T>,
+    persistence: &Persistence<T>,
+) -> Result<(), PersistError> {
+    let started_at = Instant::now();
+    let snapshot = persistence.encode(&*state.state.read().await)?;
+    persistence.save(&snapshot).await?;
+
+    tracing::debug!(
+        took = ?started_at.elapsed(),
+        bytes = snapshot.len(),
+        "saved snapshot to {}",
+        persistence.path.display()
+    );
+    Ok(())
+}
+
+/// Completes once nothing has been heard from a client for `timeout`.
+async fn silence(last_heard: &Mutex<Instant>, timeout: Duration) {
+    loop {
+        let deadline = *last_heard.lock().unwrap_or_else(PoisonError::into_inner) + timeout;
+        if Instant::now() >= deadline {
+            return;
+        }
+
+        tokio::time::sleep_until(deadline.into()).await;
     }
 }
 
-async fn index() -> Html<&'static str> {
-    Html(include_str!("html/index.html"))
+/// Completes once the server starts shutting down, see [`App::serve_with_shutdown`].
+async fn shutting_down(shutdown: &mut watch::Receiver<bool>) {
+    // NOTE: the sender lives in `ApiState`, which outlives everything waiting on this
+    let _ = shutdown.wait_for(|&shutting_down| shutting_down).await;
+}
+
+fn spawn_processing<T: Send + Sync + 'static>(
+    state: &Arc<ApiState<T>>,
+    processing: BoxFuture<'static, Result<Option<Event>, EventError>>,
+    from: SessionId,
+    connection: ConnectionId,
+    correlation: Option<&str>,
+) {
+    let processed = state.clone();
+    let correlation = correlation.map(str::to_string);
+    state.spawn(async move {
+        match processing.await {
+            Ok(Some(event)) => processed.queue(event),
+            Ok(None) => {}
+            Err(err) => processed.report_error(from, connection, correlation.as_deref(), err),
+        }
+    });
+}
+
+async fn index<T: Send + Sync + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+    uri: Uri,
+) -> Html<String> {
+    // NOTE: matches `window.location.search` so the client renders with the same params
+    let query = uri
+        .query()
+        .map(|query| format!("?{query}"))
+        .unwrap_or_default();
+
+    let rendered = match (state.ssr, state.match_route(uri.path(), query)) {
+        (Some(ssr), Some((component_name, params))) => tokio::task::spawn_blocking(move || {
+            render_on_server(ssr, &component_name, &params)
+                .map(|html| (component_name, params, html))
+        })
+        .await
+        .unwrap_or_default(),
+        _ => None,
+    };
+
+    let mount = match rendered {
+        Some((component_name, params, html)) => format!(
+            r#"<div data-pserve-id="test" data-pserve-ssr="{}" data-pserve-params="{}">{html}</div>"#,
+            escape_html(&component_name),
+            escape_html(&serde_json::to_string(&params).unwrap()),
+        ),
+        None => r#"<div data-pserve-id="test"></div>"#.to_string(),
+    };
+
+    Html(state.index_html.replacen("{{mount}}", &mount, 1))
+}
+
+/// The page lives in thread locals but dom ids come from globals, so only one can be rendered at a
+/// time.
+static SSR_LOCK: Mutex<()> = Mutex::new(());
+
+fn render_on_server(ssr: SsrFn, component_name: &str, params: &RouteParams) -> Option<String> {
+    // NOTE: a panicking render poisons the lock, but the page is reset before every render anyway
+    let _guard = SSR_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+
+    crate::client::reset();
+    let rendered = std::panic::catch_unwind(|| ssr(component_name, params));
+    crate::client::reset();
+
+    rendered.unwrap_or_else(|_| {
+        tracing::error!(
+            component_name,
+            "component panicked while rendering on the server"
+        );
+        None
+    })
 }
 
 // TODO: grab user context
@@ -357,52 +1625,203 @@ async fn ws_handler<T: Send + Sync + 'static>(
     State(state): State<Arc<ApiState<T>>>,
     ws: WebSocketUpgrade,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-) -> impl IntoResponse {
+    request_headers: HeaderMap,
+) -> Response {
+    let offered = request_headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    let Some(format) = WireFormat::negotiate(&offered) else {
+        tracing::info!(%addr, offered, "refused a client offering no known wire format");
+        return (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "unsupported subprotocols `{offered}`, expected one of {}",
+                WireFormat::ALL.map(WireFormat::protocol).join(", ")
+            ),
+        )
+            .into_response();
+    };
+
     let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
         user_agent.to_string()
     } else {
         String::from("Unknown browser")
     };
-    println!("`{user_agent}` at {addr} connected.");
 
-    let (events_to_socket, events_from_main_bus_rx) = tokio::sync::mpsc::channel(100);
-    // let (events_to_main_bus_tx, events_from_socket_rx) = tokio::sync::mpsc::channel(100);
+    let token = cookies
+        .and_then(|TypedHeader(cookies)| SessionToken::from_cookie(cookies.get(SESSION_COOKIE)?))
+        .unwrap_or_else(SessionToken::generate);
+    let session = token.id();
+    tracing::info!(%session, %addr, user_agent, "client connected");
 
-    state.connected_clients.write().await.insert(
-        addr,
-        ConnectedClient {
-            who: addr,
-            tx: events_to_socket,
-            // rx: events_from_socket_rx,
-        },
-    );
+    let cookie = token.cookie(if state.base_path.is_empty() {
+        "/"
+    } else {
+        &state.base_path
+    });
 
     let state = state.clone();
-    ws.on_upgrade(move |socket| handle_socket(socket, addr, state, events_from_main_bus_rx))
+    // NOTE: messages a bit over the limit get an error, anything way over it isn't even read
+    let mut ws = ws.max_message_size(state.limits.max_frame_size.saturating_mul(4));
+    // NOTE: a client that didn't ask for a subprotocol would fail the handshake if it got one
+    if !offered.is_empty() {
+        ws = ws.protocols([format.protocol()]);
+    }
+    let mut response = ws.on_upgrade(move |socket| async move {
+        let mut connections = state
+            .connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // NOTE: checked while holding the lock, so shutting down either waits for this socket or
+        // it's never handled at all
+        if *state.shutdown.borrow() {
+            return;
+        }
+        // NOTE: finished sockets stay in the set until they're joined
+        while connections.try_join_next().is_some() {}
+        connections.spawn(handle_socket(socket, session, format, state.clone()));
+    });
+    match HeaderValue::from_str(&cookie) {
+        Ok(cookie) => {
+            response.headers_mut().append(SET_COOKIE, cookie);
+        }
+        // NOTE: can't happen, the base path is checked in `serve_with_shutdown`
+        Err(err) => tracing::error!(%session, %err, "failed to set the session cookie"),
+    }
+
+    response
 }
 
 async fn handle_socket<T: Send + Sync + 'static>(
     socket: WebSocket,
-    who: SocketAddr,
+    who: SessionId,
+    format: WireFormat,
     state: Arc<ApiState<T>>,
-    mut events_rx: Receiver<ToClientEvent>,
 ) {
+    let connection = ConnectionId::next();
+    let mut shutdown = state.shutdown.subscribe();
+    let queue = Arc::new(ClientQueue::new(
+        connection,
+        state.queue_capacity,
+        state.slow_clients,
+        state.metrics.clone(),
+    ));
+
+    let first_connection = {
+        let mut clients = state.connected_clients.write().await;
+        let first_connection = !clients.values().any(|client| client.session == who);
+        clients.insert(
+            connection,
+            ConnectedClient {
+                session: who,
+                queue: queue.clone(),
+            },
+        );
+        first_connection
+    };
+    if first_connection {
+        state
+            .run_lifecycle_hook(&state.on_session_start, who, connection)
+            .await;
+    }
+    state
+        .run_lifecycle_hook(&state.on_connect, who, connection)
+        .await;
+
     let (mut sender, mut receiver) = socket.split();
+    let last_heard = Arc::new(Mutex::new(Instant::now()));
 
+    let send_state = state.clone();
+    let send_queue = queue.clone();
     let mut send_task = tokio::spawn(async move {
-        while let Some(event) = events_rx.recv().await {
-            sender
-                .send(Message::Text(serde_json::to_string(&event).unwrap().into()))
-                .await
-                .unwrap();
+        let interval = send_state.heartbeat.interval;
+        let mut heartbeat =
+            tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+
+        loop {
+            let event = tokio::select! {
+                event = send_queue.pop() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = heartbeat.tick() => ToClientEvent::Heartbeat,
+            };
+
+            // NOTE: heartbeats are always text, the JS glue answers those itself while binary
+            // frames wait for the wasm to load
+            let format = match event {
+                ToClientEvent::Heartbeat => WireFormat::Json,
+                _ => format,
+            };
+            let msg = match format.encode(&event) {
+                Ok(bytes) if format == WireFormat::Json => {
+                    Message::Text(String::from_utf8(bytes).unwrap().into())
+                }
+                Ok(bytes) => Message::Binary(bytes.into()),
+                Err(err) => {
+                    tracing::error!(%err, ?event, "failed to encode event");
+                    continue;
+                }
+            };
+            if sender.send(msg).await.is_err() {
+                return;
+            }
+        }
+
+        if let Some(close_frame) = send_queue.close_frame() {
+            let _ = sender.send(Message::Close(Some(close_frame))).await;
         }
     });
 
-    let state = state.clone();
+    let recv_state = state.clone();
+    let recv_last_heard = last_heard.clone();
+    let recv_queue = queue.clone();
     let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            if process_message(msg, who, &state).await.is_break() {
+        let mut limiter = Limiter::new(recv_state.limits);
+
+        while let Some(msg) = receiver.next().await {
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(err) => {
+                    if let Some(violation) = limits::unread_message(err, &recv_state.limits) {
+                        tracing::warn!(%who, %connection, %violation, "message too large to read");
+                        let _ = recv_queue.push(violation.to_event());
+                        recv_queue.drain(CloseFrame {
+                            code: close_code::SIZE,
+                            reason: "message too large to read".into(),
+                        });
+                    }
+                    break;
+                }
+            };
+
+            *recv_last_heard
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Instant::now();
+
+            if let Err(violation) = limiter.check(&msg) {
+                tracing::warn!(%who, %connection, %violation, "dropped message over the limits");
+                let _ = recv_queue.push(violation.to_event());
+
+                if limiter.exhausted() {
+                    recv_queue.drain(CloseFrame {
+                        code: close_code::POLICY,
+                        reason: "too many messages over the limits".into(),
+                    });
+                    break;
+                }
+                continue;
+            }
+
+            if process_message(msg, who, connection, &recv_state)
+                .await
+                .is_break()
+            {
                 break;
             }
         }
@@ -411,67 +1830,112 @@ async fn handle_socket<T: Send + Sync + 'static>(
     tokio::select! {
         rv_a = (&mut send_task) => {
             match rv_a {
-                Ok(()) => println!("done sending to {who}"),
-                Err(a) => println!("Error sending messages {a:?}")
+                Ok(()) => tracing::debug!(%who, %connection, "done sending"),
+                Err(err) => tracing::error!(%who, %connection, %err, "send task failed")
             }
             recv_task.abort();
         },
         rv_b = (&mut recv_task) => {
             match rv_b {
-                Ok(()) => println!("Done receiving messages"),
-                Err(b) => println!("Error receiving messages {b:?}")
+                Ok(()) => tracing::debug!(%who, %connection, "done receiving"),
+                Err(err) => tracing::error!(%who, %connection, %err, "receive task failed")
             }
+
+            // NOTE: clients kicked for breaking the limits still get told why
+            if !queue.is_draining()
+                || tokio::time::timeout(CLOSE_GRACE_PERIOD, &mut send_task)
+                    .await
+                    .is_err()
+            {
+                send_task.abort();
+            }
+        }
+        () = silence(&last_heard, state.heartbeat.timeout) => {
+            tracing::info!(%who, %connection, "client stopped answering heartbeats, disconnecting");
             send_task.abort();
+            recv_task.abort();
+        }
+        () = shutting_down(&mut shutdown) => {
+            // NOTE: the send task finishes once the queue has been drained, see
+            // `App::serve_with_shutdown`
+            recv_task.abort();
+            let _ = (&mut send_task).await;
         }
     }
 
-    println!("Websocket context {who} destroyed");
+    let last_connection = {
+        let mut clients = state.connected_clients.write().await;
+        clients.remove(&connection);
+        !clients.values().any(|client| client.session == who)
+    };
+    state.rooms.write().await.retain(|_, members| {
+        members.remove(&connection);
+        !members.is_empty()
+    });
+    state
+        .run_lifecycle_hook(&state.on_disconnect, who, connection)
+        .await;
+    if last_connection {
+        state
+            .run_lifecycle_hook(&state.on_session_end, who, connection)
+            .await;
+    }
+
+    tracing::info!(%who, %connection, "client disconnected");
 }
 
 async fn process_message<T: Send + Sync>(
     msg: Message,
-    who: SocketAddr,
+    who: SessionId,
+    connection: ConnectionId,
     state: &ApiState<T>,
 ) -> ControlFlow<(), ()> {
     match msg {
         Message::Text(t) => {
-            println!(">>> {who} sent str: {t:?}");
-            if t.starts_with("alert") {
-                state
-                    .send_to_all_clients(ToClientEvent::Alert { msg: t.to_string() })
-                    .await;
-            } else if let Ok(value) = serde_json::from_str::<ToServerEvent>(&t) {
-                tracing::info!("received ToServerEvent: {value:?}");
-                state.send_to_server(who, value).await;
-            } else if let Ok(value) = serde_json::from_str(&t) {
-                tracing::info!("received Custom Event: {value:?}");
-                state
-                    .send_to_server(who, ToServerEvent::Custom(value))
-                    .await;
-            } else {
-                println!(">>> {who} sent invalid json: {t:?}");
+            tracing::trace!(%who, %connection, text = %t, "received text message");
+
+            match ToServerEvent::decode(WireFormat::Json, t.as_bytes()) {
+                // NOTE: hearing anything at all is what counts, see `handle_socket`
+                Ok(ToServerEvent::Heartbeat) => {}
+                Ok(value) => {
+                    tracing::trace!("received ToServerEvent: {value:?}");
+                    state.send_to_server(who, connection, value);
+                }
+                Err(err) => state.report_error(
+                    who,
+                    connection,
+                    None,
+                    EventError::new("invalid_message", err.to_string()),
+                ),
             }
         }
-        Message::Binary(d) => {
-            println!(">>> {} sent {} bytes: {:?}", who, d.len(), d);
-        }
+        Message::Binary(d) => match ToServerEvent::decode(WireFormat::MessagePack, &d) {
+            Ok(ToServerEvent::Heartbeat) => {}
+            Ok(value) => {
+                tracing::trace!("received ToServerEvent: {value:?}");
+                state.send_to_server(who, connection, value);
+            }
+            Err(err) => state.report_error(
+                who,
+                connection,
+                None,
+                EventError::new("invalid_message", err.to_string()),
+            ),
+        },
         Message::Close(c) => {
             if let Some(cf) = c {
-                println!(
-                    ">>> {} sent close with code {} and reason `{}`",
-                    who, cf.code, cf.reason
-                );
+                tracing::debug!(%who, %connection, code = cf.code, reason = %cf.reason, "client closed the connection");
             } else {
-                println!(">>> {who} somehow sent close message without CloseFrame");
+                tracing::debug!(%who, %connection, "client closed the connection without a close frame");
             }
             return ControlFlow::Break(());
         }
 
         Message::Pong(v) => {
-            println!(">>> {who} sent pong with {v:?}");
+            tracing::trace!(%who, %connection, ?v, "received pong");
         }
         Message::Ping(v) => {
-            println!(">>> {who} sent ping with {v:?}");
+            tracing::trace!(%who, %connection, ?v, "received ping");
         }
     }
     ControlFlow::Continue(())
@@ -0,0 +1,317 @@
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    body::Bytes,
+    http::{HeaderValue, StatusCode, header::CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{CacheControl, ETag, IfModifiedSince, IfNoneMatch, LastModified},
+};
+use sha1::{Digest, Sha1};
+
+/// Where the files under an [`App::assets`](super::App::assets) prefix come from.
+pub(super) enum Assets {
+    /// Read from disk on every request, meant for development
+    Dir(PathBuf),
+    /// Compiled into the binary, meant for release builds
+    Embedded(HashMap<String, EmbeddedAsset>),
+}
+
+pub(super) struct EmbeddedAsset {
+    contents: &'static [u8],
+    etag: ETag,
+}
+
+impl Assets {
+    pub(super) fn embedded(files: &[(&str, &'static [u8])]) -> Self {
+        let files = files
+            .iter()
+            .map(|&(name, contents)| {
+                let etag = format!("\"{}\"", content_hash(contents)).parse().unwrap();
+
+                (
+                    name.trim_start_matches('/').to_string(),
+                    EmbeddedAsset { contents, etag },
+                )
+            })
+            .collect();
+
+        Assets::Embedded(files)
+    }
+
+    pub(super) async fn serve(
+        &self,
+        path: &str,
+        started_at: SystemTime,
+        if_none_match: Option<IfNoneMatch>,
+        if_modified_since: Option<IfModifiedSince>,
+    ) -> Response {
+        let Some(path) = sanitize(path) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+
+        let (etag, last_modified, cache_control, contents) = match self {
+            Assets::Dir(dir) => {
+                let path = dir.join(&path);
+                let Ok(metadata) = tokio::fs::metadata(&path).await else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                if !metadata.is_file() {
+                    return StatusCode::NOT_FOUND.into_response();
+                }
+
+                // NOTE: files change all the time during development, so always revalidate
+                let modified = metadata.modified().unwrap_or(started_at);
+                let since_epoch = modified
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default();
+                let etag = format!("W/\"{:x}-{:x}\"", metadata.len(), since_epoch.as_nanos())
+                    .parse()
+                    .unwrap();
+
+                let contents = Contents::Dir(path);
+                (
+                    etag,
+                    modified,
+                    CacheControl::new().with_no_cache(),
+                    contents,
+                )
+            }
+            Assets::Embedded(files) => {
+                let Some(asset) = files.get(path.to_str().unwrap_or_default()) else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+
+                let cache_control = CacheControl::new()
+                    .with_public()
+                    .with_max_age(Duration::from_secs(60 * 60));
+                let contents = Contents::Embedded(asset.contents);
+                (asset.etag.clone(), started_at, cache_control, contents)
+            }
+        };
+
+        let not_modified = match (if_none_match, if_modified_since) {
+            (Some(if_none_match), _) => !if_none_match.precondition_passes(&etag),
+            (None, Some(if_modified_since)) => !if_modified_since.is_modified(last_modified),
+            (None, None) => false,
+        };
+
+        let headers = (
+            TypedHeader(etag),
+            TypedHeader(LastModified::from(last_modified)),
+            TypedHeader(cache_control),
+        );
+        if not_modified {
+            return (StatusCode::NOT_MODIFIED, headers).into_response();
+        }
+
+        let contents = match contents {
+            Contents::Dir(path) => match tokio::fs::read(&path).await {
+                Ok(contents) => Bytes::from(contents),
+                Err(err) => {
+                    tracing::warn!(path = %path.display(), %err, "failed to read asset");
+                    return StatusCode::NOT_FOUND.into_response();
+                }
+            },
+            Contents::Embedded(contents) => Bytes::from_static(contents),
+        };
+
+        (
+            headers,
+            [(CONTENT_TYPE, HeaderValue::from_static(content_type(&path)))],
+            contents,
+        )
+            .into_response()
+    }
+}
+
+enum Contents {
+    Dir(PathBuf),
+    Embedded(&'static [u8]),
+}
+
+/// Only allows plain relative paths, so nothing outside of the asset directory can be reached.
+fn sanitize(path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_)))
+        .then(|| path.to_path_buf())
+}
+
+/// A short hex digest of `contents`, used to tell versions of a file apart.
+pub(super) fn content_hash(contents: &[u8]) -> String {
+    Sha1::digest(contents)[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "wasm" => "application/wasm",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "pdf" => "application/pdf",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Embeds files from a directory relative to the crate's `Cargo.toml`, for
+/// [`App::embedded_assets`](crate::server::App::embedded_assets).
+///
+/// ```ignore
+/// app.embedded_assets("/static", pserve::embed_assets!("static", ["style.css", "logo.png"]))
+/// ```
+#[macro_export]
+macro_rules! embed_assets {
+    ($dir:literal, [$($file:literal),* $(,)?]) => {
+        &[$((
+            $file,
+            include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/", $dir, "/", $file)).as_slice(),
+        )),*]
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::{ETAG, LAST_MODIFIED};
+
+    use super::*;
+
+    fn embedded() -> Assets {
+        Assets::embedded(&[("/style.css", b"body {}"), ("blob.bin", b"\0\x01")])
+    }
+
+    #[test]
+    fn sanitize_only_allows_plain_relative_paths() {
+        assert_eq!(
+            sanitize("css/style.css"),
+            Some(PathBuf::from("css/style.css"))
+        );
+        assert_eq!(sanitize(".."), None);
+        assert_eq!(sanitize("../Cargo.toml"), None);
+        assert_eq!(sanitize("a/../b"), None);
+        assert_eq!(sanitize("./a"), None);
+        assert_eq!(sanitize("/etc/passwd"), None);
+    }
+
+    #[test]
+    fn unknown_extensions_are_octet_streams() {
+        assert_eq!(
+            content_type(Path::new("style.CSS")),
+            "text/css; charset=utf-8"
+        );
+        assert_eq!(
+            content_type(Path::new("blob.bin")),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            content_type(Path::new("README")),
+            "application/octet-stream"
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_embedded_files() {
+        let response = embedded()
+            .serve("style.css", SystemTime::now(), None, None)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/css; charset=utf-8");
+
+        let response = embedded()
+            .serve("blob.bin", SystemTime::now(), None, None)
+            .await;
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/octet-stream");
+
+        let response = embedded()
+            .serve("missing.css", SystemTime::now(), None, None)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn matching_etags_are_not_modified() {
+        let assets = embedded();
+        let started_at = SystemTime::now();
+
+        let response = assets.serve("style.css", started_at, None, None).await;
+        let etag: ETag = response.headers()[ETAG].to_str().unwrap().parse().unwrap();
+
+        let response = assets
+            .serve("style.css", started_at, Some(etag.into()), None)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let other: ETag = "\"something-else\"".parse().unwrap();
+        let response = assets
+            .serve("style.css", started_at, Some(other.into()), None)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn unchanged_since_is_not_modified() {
+        let assets = embedded();
+        let started_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let response = assets.serve("style.css", started_at, None, None).await;
+        assert!(response.headers().contains_key(LAST_MODIFIED));
+
+        let since = IfModifiedSince::from(started_at + Duration::from_secs(60));
+        let response = assets
+            .serve("style.css", started_at, None, Some(since))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let since = IfModifiedSince::from(started_at - Duration::from_secs(60));
+        let response = assets
+            .serve("style.css", started_at, None, Some(since))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn dirs_refuse_paths_outside_of_them() {
+        let assets = Assets::Dir(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src"));
+
+        let response = assets.serve("lib.rs", SystemTime::now(), None, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for path in ["../Cargo.toml", "server/../lib.rs", "/etc/passwd"] {
+            let response = assets.serve(path, SystemTime::now(), None, None).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::{fmt, time::Duration};
+
+use super::Event;
+
+/// An error sent back to the client whose event caused it, as a [`ToClientEvent::Error`].
+///
+/// [`ToClientEvent::Error`]: crate::protocol::ToClientEvent::Error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventError {
+    pub code: String,
+    pub message: String,
+}
+
+impl EventError {
+    /// `code` is meant for the client's code to match on, `message` for people.
+    pub fn new(code: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            code: code.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for EventError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for EventError {}
+
+/// Something about the [`App`](super::App) that only shows once it starts serving.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// There's nothing to serve the client from, see [`App::wasm`](super::App::wasm)
+    MissingWasm,
+    /// A snapshot version or migration was set up, but the state isn't persisted, see
+    /// [`App::persist`](super::App::persist)
+    NotPersisted,
+    /// A migration starts at or past the version snapshots are saved as
+    UnreachableMigration { from: u32, version: u32 },
+    /// A route pattern passed to [`App::route`](super::App::route) can't be matched against
+    InvalidRoute { path: String, err: String },
+    /// The [`App::base_path`](super::App::base_path) can't be used as the session cookie's
+    /// path, it has to be printable ascii without `;`
+    InvalidBasePath { base_path: String },
+    /// Something that runs on an interval was set up to run every zero seconds
+    ZeroInterval { setting: &'static str },
+    /// The heartbeat timeout passed to [`App::heartbeat`](super::App::heartbeat) runs out before
+    /// the next heartbeat is sent
+    HeartbeatTimeout {
+        interval: Duration,
+        timeout: Duration,
+    },
+    /// [`App::max_frame_size`](super::App::max_frame_size) was zero, which leaves no room for
+    /// any message
+    ZeroFrameSize,
+    /// An event passed to [`App::on`](super::App::on) is neither an externally tagged enum nor a
+    /// struct, so there's no tag to route it by
+    UntaggedEvent { event: &'static str },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingWasm => write!(f, "wasm blob not provided, see `App::wasm`"),
+            ConfigError::NotPersisted => {
+                write!(
+                    f,
+                    "snapshots are versioned but never saved, see `App::persist`"
+                )
+            }
+            ConfigError::UnreachableMigration { from, version } => write!(
+                f,
+                "migration from version {from} never runs, snapshots are version {version}"
+            ),
+            ConfigError::InvalidRoute { path, err } => write!(f, "invalid route `{path}`: {err}"),
+            ConfigError::InvalidBasePath { base_path } => {
+                write!(f, "`{base_path}` can't be used as a cookie path")
+            }
+            ConfigError::ZeroInterval { setting } => {
+                write!(f, "the interval passed to `{setting}` can't be zero")
+            }
+            ConfigError::HeartbeatTimeout { interval, timeout } => write!(
+                f,
+                "heartbeat timeout of {timeout:?} has to be longer than the {interval:?} interval"
+            ),
+            ConfigError::ZeroFrameSize => {
+                write!(f, "the size passed to `App::max_frame_size` can't be zero")
+            }
+            ConfigError::UntaggedEvent { event } => write!(
+                f,
+                "`{event}` can't be routed by tag, use an externally tagged enum or a struct"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// What processors can return: maybe an event to queue, or an error for the client that sent
+/// the event being processed.
+pub trait ProcessorOutput {
+    fn into_result(self) -> Result<Option<Event>, EventError>;
+}
+
+impl ProcessorOutput for Option<Event> {
+    fn into_result(self) -> Result<Option<Event>, EventError> {
+        Ok(self)
+    }
+}
+
+impl ProcessorOutput for Result<Option<Event>, EventError> {
+    fn into_result(self) -> Result<Option<Event>, EventError> {
+        self
+    }
+}
+
+impl ProcessorOutput for Result<(), EventError> {
+    fn into_result(self) -> Result<Option<Event>, EventError> {
+        self.map(|()| None)
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+use super::{ConnectionId, SessionId};
+
+/// A custom event that reached the processors, one per line of the journal, see
+/// [`App::journal`](super::App::journal).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Milliseconds since the unix epoch
+    pub at: u64,
+    pub from: SessionId,
+    pub connection: ConnectionId,
+    pub event: serde_json::Value,
+}
+
+/// Appends to the journal file, which is opened when the server starts.
+pub(super) struct Journal {
+    path: PathBuf,
+    file: Mutex<tokio::fs::File>,
+}
+
+impl Journal {
+    pub(super) async fn open(path: PathBuf) -> io::Result<Self> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Written before the event is processed, so a crash halfway through processing it still
+    /// leaves it in the journal.
+    pub(super) async fn append(
+        &self,
+        from: SessionId,
+        connection: ConnectionId,
+        event: &serde_json::Value,
+    ) {
+        let entry = JournalEntry {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_millis() as u64)
+                .unwrap_or_default(),
+            from,
+            connection,
+            event: event.clone(),
+        };
+
+        // NOTE: a `Value` and two ids can't fail to serialize
+        let mut line = serde_json::to_vec(&entry).unwrap();
+        line.push(b'\n');
+
+        let mut file = self.file.lock().await;
+        if let Err(err) = async {
+            file.write_all(&line).await?;
+            file.flush().await
+        }
+        .await
+        {
+            tracing::error!("failed to journal event to {}: {err}", self.path.display());
+        }
+    }
+}
+
+/// Reads every entry of the journal at `path`, in the order they were written.
+pub(super) fn read(path: &Path) -> Result<Vec<JournalEntry>, ReplayError> {
+    let journal = std::fs::read_to_string(path).map_err(ReplayError::Io)?;
+
+    journal
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(at, line)| {
+            serde_json::from_str(line).map_err(|error| ReplayError::Entry {
+                line: at + 1,
+                error,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    /// The journal has a line that isn't a [`JournalEntry`], counting from 1
+    Entry {
+        line: usize,
+        error: serde_json::Error,
+    },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(err) => write!(f, "io: {err}"),
+            ReplayError::Entry { line, error } => {
+                write!(f, "invalid journal entry on line {line}: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReplayError::Io(err) => Some(err),
+            ReplayError::Entry { error, .. } => Some(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::server::{App, Ctx, Event, EventError, session::SessionToken};
+
+    #[derive(Default)]
+    struct Counter {
+        total: i64,
+        events: usize,
+    }
+
+    #[derive(Deserialize)]
+    enum CounterEvent {
+        Add(i64),
+    }
+
+    fn add(
+        state: &mut Counter,
+        _ctx: Ctx,
+        CounterEvent::Add(n): CounterEvent,
+    ) -> Result<(), EventError> {
+        if n < 0 {
+            return Err(EventError::new("negative", "counters only go up"));
+        }
+
+        state.total += n;
+        Ok(())
+    }
+
+    fn count(state: &mut Counter, _ctx: Ctx, _event: serde_json::Value) -> Option<Event> {
+        state.events += 1;
+        None
+    }
+
+    fn journal_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pserve-{name}-{}.jsonl", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn replays_what_was_written() {
+        let path = journal_path("replay");
+        let _ = std::fs::remove_file(&path);
+
+        let journal = Journal::open(path.clone()).await.unwrap();
+        let from = SessionToken::generate().id();
+        let connection = ConnectionId::next();
+        for event in [
+            json!({ "Add": 2 }),
+            json!({ "Add": -5 }),
+            json!({ "Unknown": true }),
+            json!({ "Add": 3 }),
+        ] {
+            journal.append(from, connection, &event).await;
+        }
+        drop(journal);
+
+        let entries = read(&path).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert!(entries.iter().all(|entry| entry.from == from));
+        assert_eq!(entries[1].event, json!({ "Add": -5 }));
+
+        let state = App::<Counter>::default()
+            .on(add)
+            .add_processor(count)
+            .replay(&path)
+            .unwrap();
+        assert_eq!(state.total, 5);
+        assert_eq!(state.events, 4);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_line() {
+        let path = journal_path("invalid");
+        let entry = JournalEntry {
+            at: 0,
+            from: SessionToken::generate().id(),
+            connection: ConnectionId::next(),
+            event: json!({ "Add": 1 }),
+        };
+        let line = serde_json::to_string(&entry).unwrap();
+        std::fs::write(&path, format!("{line}\n\n{{\"at\":1}}\n")).unwrap();
+
+        assert!(matches!(
+            read(&path),
+            Err(ReplayError::Entry { line: 3, .. })
+        ));
+        assert!(matches!(
+            read(&journal_path("missing")),
+            Err(ReplayError::Io(_))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
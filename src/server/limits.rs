@@ -0,0 +1,227 @@
+use std::{fmt, time::Instant};
+
+use axum::extract::ws::Message;
+use tungstenite::error::CapacityError;
+
+use crate::protocol::ToClientEvent;
+
+/// A connection is closed after this many violations in a row.
+const MAX_STRIKES: u32 = 10;
+
+/// What a single connection is allowed to send, see [`App::rate_limit`](super::App::rate_limit)
+/// and [`App::max_frame_size`](super::App::max_frame_size).
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Limits {
+    pub(super) per_second: u32,
+    pub(super) burst: u32,
+    pub(super) max_frame_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            per_second: 20,
+            burst: 50,
+            max_frame_size: 64 * 1024,
+        }
+    }
+}
+
+/// A token bucket for a single connection, refilled at [`Limits::per_second`] up to
+/// [`Limits::burst`].
+pub(super) struct Limiter {
+    limits: Limits,
+    tokens: f64,
+    refilled_at: Instant,
+    strikes: u32,
+}
+
+#[derive(Debug)]
+pub(super) enum Violation {
+    FrameTooLarge { size: usize, max: usize },
+    RateLimited,
+}
+
+impl Limiter {
+    pub(super) fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            tokens: limits.burst as f64,
+            refilled_at: Instant::now(),
+            strikes: 0,
+        }
+    }
+
+    /// Takes a token for `msg` if it's within the limits. Control frames are always fine, the
+    /// websocket itself needs those.
+    pub(super) fn check(&mut self, msg: &Message) -> Result<(), Violation> {
+        let size = match msg {
+            Message::Text(text) => text.len(),
+            Message::Binary(bytes) => bytes.len(),
+            Message::Ping(_) | Message::Pong(_) | Message::Close(_) => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let refill =
+            now.duration_since(self.refilled_at).as_secs_f64() * self.limits.per_second as f64;
+        self.tokens = (self.tokens + refill).min(self.limits.burst as f64);
+        self.refilled_at = now;
+
+        let verdict = if size > self.limits.max_frame_size {
+            Err(Violation::FrameTooLarge {
+                size,
+                max: self.limits.max_frame_size,
+            })
+        } else if self.tokens < 1.0 {
+            Err(Violation::RateLimited)
+        } else {
+            self.tokens -= 1.0;
+            Ok(())
+        };
+
+        match verdict {
+            Ok(()) => self.strikes = 0,
+            Err(_) => self.strikes += 1,
+        }
+        verdict
+    }
+
+    /// Whether the client kept breaking the limits and should be disconnected.
+    pub(super) fn exhausted(&self) -> bool {
+        self.strikes >= MAX_STRIKES
+    }
+}
+
+/// Messages way over [`Limits::max_frame_size`] aren't even read, see
+/// [`App::max_frame_size`](super::App::max_frame_size). Picks those out of everything else a
+/// websocket can fail with.
+pub(super) fn unread_message(err: axum::Error, limits: &Limits) -> Option<Violation> {
+    match *err.into_inner().downcast::<tungstenite::Error>().ok()? {
+        tungstenite::Error::Capacity(CapacityError::MessageTooLong { size, .. }) => {
+            Some(Violation::FrameTooLarge {
+                size,
+                max: limits.max_frame_size,
+            })
+        }
+        _ => None,
+    }
+}
+
+impl Violation {
+    pub(super) fn code(&self) -> &'static str {
+        match self {
+            Violation::FrameTooLarge { .. } => "frame_too_large",
+            Violation::RateLimited => "rate_limited",
+        }
+    }
+
+    /// What the client is told about the message it sent, it was dropped either way.
+    pub(super) fn to_event(&self) -> ToClientEvent {
+        ToClientEvent::Error {
+            code: self.code().to_string(),
+            message: self.to_string(),
+            correlation: None,
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::FrameTooLarge { size, max } => {
+                write!(
+                    f,
+                    "message of {size} bytes is over the limit of {max} bytes"
+                )
+            }
+            Violation::RateLimited => write!(f, "too many messages, slow down"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn limiter(per_second: u32, burst: u32, max_frame_size: usize) -> Limiter {
+        Limiter::new(Limits {
+            per_second,
+            burst,
+            max_frame_size,
+        })
+    }
+
+    fn text(len: usize) -> Message {
+        Message::Text("a".repeat(len).into())
+    }
+
+    /// Pretends `elapsed` went by since the bucket was last refilled.
+    fn wait(limiter: &mut Limiter, elapsed: Duration) {
+        limiter.refilled_at -= elapsed;
+    }
+
+    #[test]
+    fn burst_then_rate_limited() {
+        let mut limiter = limiter(1, 3, 16);
+
+        for _ in 0..3 {
+            assert!(limiter.check(&text(1)).is_ok());
+        }
+        assert!(matches!(
+            limiter.check(&text(1)),
+            Err(Violation::RateLimited)
+        ));
+    }
+
+    #[test]
+    fn refills_over_time_up_to_the_burst() {
+        let mut limiter = limiter(10, 5, 16);
+        for _ in 0..5 {
+            limiter.check(&text(1)).unwrap();
+        }
+
+        wait(&mut limiter, Duration::from_millis(250));
+        assert!(limiter.check(&text(1)).is_ok());
+        assert!(limiter.check(&text(1)).is_ok());
+        assert!(limiter.check(&text(1)).is_err());
+
+        wait(&mut limiter, Duration::from_secs(60));
+        for _ in 0..5 {
+            assert!(limiter.check(&text(1)).is_ok());
+        }
+        assert!(limiter.check(&text(1)).is_err());
+    }
+
+    #[test]
+    fn frames_over_the_limit() {
+        let mut limiter = limiter(10, 10, 16);
+
+        assert!(limiter.check(&text(16)).is_ok());
+        assert!(matches!(
+            limiter.check(&text(17)),
+            Err(Violation::FrameTooLarge { size: 17, max: 16 })
+        ));
+        assert!(limiter.check(&Message::Ping(vec![0; 64].into())).is_ok());
+    }
+
+    #[test]
+    fn strikes_add_up_and_reset() {
+        let mut limiter = limiter(10, 10, 16);
+
+        for _ in 0..MAX_STRIKES - 1 {
+            assert!(limiter.check(&text(17)).is_err());
+        }
+        assert!(!limiter.exhausted());
+
+        limiter.check(&text(1)).unwrap();
+        for _ in 0..MAX_STRIKES - 1 {
+            assert!(limiter.check(&text(17)).is_err());
+        }
+        assert!(!limiter.exhausted());
+
+        assert!(limiter.check(&text(17)).is_err());
+        assert!(limiter.exhausted());
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+/// Counters for what the server is doing, see [`App::metrics`](super::App::metrics).
+///
+/// Cheap to clone, every clone reads the same counters.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    queued: AtomicUsize,
+    peak_queue_depth: AtomicUsize,
+    dropped: AtomicU64,
+    coalesced: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+/// The counters of [`Metrics`] at one point in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Events waiting to be sent, across all clients
+    pub queued: usize,
+    /// The most events that have been waiting for a single client at once
+    pub peak_queue_depth: usize,
+    /// Events thrown away to make room for newer ones
+    pub dropped: u64,
+    /// State updates merged into ones that were still waiting
+    pub coalesced: u64,
+    /// Clients disconnected for not keeping up
+    pub disconnected: u64,
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let counters = &self.inner;
+
+        MetricsSnapshot {
+            queued: counters.queued.load(Ordering::Relaxed),
+            peak_queue_depth: counters.peak_queue_depth.load(Ordering::Relaxed),
+            dropped: counters.dropped.load(Ordering::Relaxed),
+            coalesced: counters.coalesced.load(Ordering::Relaxed),
+            disconnected: counters.disconnected.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(super) fn queued(&self, depth: usize) {
+        self.inner.queued.fetch_add(1, Ordering::Relaxed);
+        self.inner
+            .peak_queue_depth
+            .fetch_max(depth, Ordering::Relaxed);
+    }
+
+    pub(super) fn unqueued(&self, count: usize) {
+        self.inner.queued.fetch_sub(count, Ordering::Relaxed);
+    }
+
+    pub(super) fn dropped(&self) {
+        self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn coalesced(&self) {
+        self.inner.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn disconnected(&self) {
+        self.inner.disconnected.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+
+/// Turns a snapshot of one version into the next one, see
+/// [`App::migration`](super::App::migration).
+pub type MigrateFn = fn(Value) -> Result<Value, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Where and how often the state is saved, see [`App::persist`](super::App::persist).
+pub(super) struct Persistence<T> {
+    pub(super) path: PathBuf,
+    pub(super) interval: Duration,
+    pub(super) migrations: Migrations,
+    encode: fn(&T, u32) -> Result<Vec<u8>, PersistError>,
+    decode: fn(Value) -> Result<T, PersistError>,
+}
+
+/// The version snapshots are saved as, and how to get older ones there one version at a time.
+#[derive(Default)]
+pub(super) struct Migrations {
+    pub(super) version: u32,
+    steps: BTreeMap<u32, MigrateFn>,
+}
+
+impl Migrations {
+    pub(super) fn add(&mut self, from: u32, migrate: MigrateFn) {
+        self.steps.insert(from, migrate);
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.version == 0 && self.steps.is_empty()
+    }
+
+    /// The first step that can never run, since it starts at or past the current version.
+    pub(super) fn unreachable(&self) -> Option<u32> {
+        self.steps
+            .range(self.version..)
+            .next()
+            .map(|(from, _)| *from)
+    }
+
+    /// Runs every step from `version` up to the current one, in order.
+    pub(super) fn migrate(
+        &self,
+        mut version: u32,
+        mut state: Value,
+    ) -> Result<Value, PersistError> {
+        let current = self.version;
+        if version > current {
+            return Err(PersistError::TooNew { version, current });
+        }
+
+        while version < current {
+            let migrate = self
+                .steps
+                .get(&version)
+                .ok_or(PersistError::MissingMigration {
+                    from: version,
+                    current,
+                })?;
+
+            state = migrate(state).map_err(|error| PersistError::Migrate {
+                from: version,
+                error,
+            })?;
+            version += 1;
+        }
+
+        Ok(state)
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    version: u32,
+    state: &'a T,
+}
+
+#[derive(Deserialize)]
+struct RawEnvelope {
+    version: u32,
+    state: Value,
+}
+
+#[derive(Debug)]
+pub enum PersistError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// The snapshot was written by a newer version of the app
+    TooNew {
+        version: u32,
+        current: u32,
+    },
+    /// The snapshot is older and there's no [`MigrateFn`] for one of the versions in between
+    MissingMigration {
+        from: u32,
+        current: u32,
+    },
+    /// The [`MigrateFn`] from version `from` to the next one failed
+    Migrate {
+        from: u32,
+        error: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+impl<T: Serialize + DeserializeOwned> Persistence<T> {
+    pub(super) fn new(path: PathBuf, interval: Duration) -> Self {
+        Self {
+            path,
+            interval,
+            migrations: Migrations::default(),
+            encode: |state, version| {
+                serde_json::to_vec(&Envelope { version, state }).map_err(PersistError::Json)
+            },
+            decode: |state| serde_json::from_value(state).map_err(PersistError::Json),
+        }
+    }
+}
+
+impl<T> Persistence<T> {
+    /// Reads the latest snapshot, `None` if there isn't one yet.
+    pub(super) async fn load(&self) -> Result<Option<T>, PersistError> {
+        let bytes = match tokio::fs::read(&self.path).await {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(PersistError::Io(err)),
+        };
+
+        let RawEnvelope { version, state } =
+            serde_json::from_slice(&bytes).map_err(PersistError::Json)?;
+        let state = self.migrations.migrate(version, state)?;
+
+        (self.decode)(state).map(Some)
+    }
+
+    /// Only needs the state for as long as it takes to serialize it.
+    pub(super) fn encode(&self, state: &T) -> Result<Vec<u8>, PersistError> {
+        (self.encode)(state, self.migrations.version)
+    }
+
+    /// Writes next to the snapshot first and then renames over it, so a crash halfway through
+    /// leaves the previous snapshot intact.
+    pub(super) async fn save(&self, snapshot: &[u8]) -> Result<(), PersistError> {
+        let tmp = tmp_path(&self.path);
+
+        let mut file = tokio::fs::File::create(&tmp)
+            .await
+            .map_err(PersistError::Io)?;
+        file.write_all(snapshot).await.map_err(PersistError::Io)?;
+        file.sync_all().await.map_err(PersistError::Io)?;
+        drop(file);
+
+        tokio::fs::rename(&tmp, &self.path)
+            .await
+            .map_err(PersistError::Io)?;
+        sync_parent(&self.path).await.map_err(PersistError::Io)
+    }
+}
+
+/// The rename only survives a crash once the directory it happened in is synced too.
+#[cfg(unix)]
+async fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    tokio::fs::File::open(parent).await?.sync_all().await
+}
+
+// NOTE: directories can't be opened like files elsewhere, and renames are durable on their own
+#[cfg(not(unix))]
+async fn sync_parent(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = OsString::from(path.as_os_str());
+    tmp.push(".tmp");
+    tmp.into()
+}
+
+impl fmt::Display for PersistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PersistError::Io(err) => write!(f, "io: {err}"),
+            PersistError::Json(err) => write!(f, "json: {err}"),
+            PersistError::TooNew { version, current } => {
+                write!(
+                    f,
+                    "snapshot is version {version}, newer than the current {current}"
+                )
+            }
+            PersistError::MissingMigration { from, current } => write!(
+                f,
+                "no migration from snapshot version {from}, needed to get to {current}"
+            ),
+            PersistError::Migrate { from, error } => write!(
+                f,
+                "failed to migrate snapshot from version {from} to {}: {error}",
+                from + 1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PersistError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PersistError::Io(err) => Some(err),
+            PersistError::Json(err) => Some(err),
+            PersistError::TooNew { .. } | PersistError::MissingMigration { .. } => None,
+            PersistError::Migrate { error, .. } => Some(error.as_ref()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn migrations(version: u32, steps: &[(u32, MigrateFn)]) -> Migrations {
+        let mut migrations = Migrations {
+            version,
+            ..Migrations::default()
+        };
+        for (from, migrate) in steps {
+            migrations.add(*from, *migrate);
+        }
+        migrations
+    }
+
+    fn add_name(mut state: Value) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+        state["name"] = json!("pserve");
+        Ok(state)
+    }
+
+    fn count_to_list(mut state: Value) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+        let count = state["count"].take();
+        state["counts"] = json!([count]);
+        Ok(state)
+    }
+
+    #[test]
+    fn runs_every_step_in_order() {
+        let migrations = migrations(2, &[(1, count_to_list), (0, add_name)]);
+
+        let state = migrations.migrate(0, json!({ "count": 3 })).unwrap();
+        assert_eq!(
+            state,
+            json!({ "name": "pserve", "count": null, "counts": [3] })
+        );
+
+        let state = migrations.migrate(1, json!({ "count": 3 })).unwrap();
+        assert_eq!(state, json!({ "count": null, "counts": [3] }));
+    }
+
+    #[test]
+    fn current_version_is_left_alone() {
+        let migrations = migrations(1, &[(0, add_name)]);
+
+        assert_eq!(migrations.migrate(1, json!({})).unwrap(), json!({}));
+    }
+
+    #[test]
+    fn missing_step() {
+        let migrations = migrations(3, &[(0, add_name), (2, count_to_list)]);
+
+        assert!(matches!(
+            migrations.migrate(0, json!({ "count": 3 })),
+            Err(PersistError::MissingMigration {
+                from: 1,
+                current: 3
+            })
+        ));
+        assert!(migrations.migrate(2, json!({ "count": 3 })).is_ok());
+    }
+
+    #[test]
+    fn failed_step() {
+        let migrations = migrations(2, &[(0, add_name), (1, |_| Err("no counts".into()))]);
+
+        let err = migrations.migrate(0, json!({})).unwrap_err();
+        assert!(matches!(err, PersistError::Migrate { from: 1, .. }));
+        assert_eq!(
+            err.to_string(),
+            "failed to migrate snapshot from version 1 to 2: no counts"
+        );
+    }
+
+    #[test]
+    fn newer_snapshot() {
+        assert!(matches!(
+            migrations(1, &[]).migrate(2, json!({})),
+            Err(PersistError::TooNew {
+                version: 2,
+                current: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn unreachable_steps() {
+        assert_eq!(
+            migrations(2, &[(0, add_name), (1, add_name)]).unreachable(),
+            None
+        );
+        assert_eq!(migrations(2, &[(2, add_name)]).unreachable(), Some(2));
+        assert_eq!(migrations(0, &[(0, add_name)]).unreachable(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn save_then_load() {
+        let dir = std::env::temp_dir().join(format!("pserve-persist-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snapshot.json");
+
+        let mut old = Persistence::<Value>::new(path.clone(), Duration::from_secs(1));
+        assert!(old.load().await.unwrap().is_none());
+        old.save(&old.encode(&json!({ "count": 3 })).unwrap())
+            .await
+            .unwrap();
+        assert!(!tmp_path(&path).exists());
+
+        old.migrations = migrations(1, &[(0, count_to_list)]);
+        assert_eq!(
+            old.load().await.unwrap(),
+            Some(json!({ "count": null, "counts": [3] }))
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
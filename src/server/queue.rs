@@ -0,0 +1,413 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use axum::extract::ws::CloseFrame;
+use serde_json::Value;
+use tokio::sync::Notify;
+
+use super::{ConnectionId, metrics::Metrics};
+use crate::protocol::ToClientEvent;
+
+/// What to do once a client stops keeping up and its queue fills, see
+/// [`App::slow_clients`](super::App::slow_clients).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    /// Make room by dropping the event that's been waiting the longest, the client might miss
+    /// state updates
+    #[default]
+    DropOldest,
+    /// Once the queue is full, merge state updates into any still waiting for the same state, so
+    /// a client that falls behind only gets the latest values. Drops the oldest event if there's
+    /// nothing to merge into
+    Coalesce,
+    /// Close the connection, the client asks for the full state again once it's reconnected
+    Disconnect,
+}
+
+/// The events waiting to be written to a single connection's socket.
+///
+/// Pushing never waits, so a slow client can't hold up delivery to everyone else.
+pub(super) struct ClientQueue {
+    connection: ConnectionId,
+    inner: Mutex<Inner>,
+    ready: Notify,
+    capacity: usize,
+    policy: SlowClientPolicy,
+    metrics: Metrics,
+}
+
+struct Inner {
+    events: VecDeque<ToClientEvent>,
+    closed: bool,
+    /// Whether the client fell behind before, so that's only logged once
+    overflowed: bool,
+    /// Set once the queue is being drained, sent after the last event
+    close_frame: Option<CloseFrame>,
+}
+
+/// The client fell too far behind and was disconnected, see [`SlowClientPolicy::Disconnect`].
+#[derive(Debug)]
+pub(super) struct Overflowed;
+
+impl ClientQueue {
+    pub(super) fn new(
+        connection: ConnectionId,
+        capacity: usize,
+        policy: SlowClientPolicy,
+        metrics: Metrics,
+    ) -> Self {
+        Self {
+            connection,
+            inner: Mutex::new(Inner {
+                events: VecDeque::new(),
+                closed: false,
+                overflowed: false,
+                close_frame: None,
+            }),
+            ready: Notify::new(),
+            capacity: capacity.max(1),
+            policy,
+            metrics,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // NOTE: nothing in here can panic halfway through changing the queue
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(super) fn push(&self, event: ToClientEvent) -> Result<(), Overflowed> {
+        let mut inner = self.lock();
+        if inner.closed {
+            return Err(Overflowed);
+        }
+
+        if inner.events.len() >= self.capacity {
+            if self.policy == SlowClientPolicy::Coalesce
+                && inner
+                    .events
+                    .iter_mut()
+                    .rev()
+                    .any(|queued| coalesce(queued, &event))
+            {
+                self.metrics.coalesced();
+                return Ok(());
+            }
+
+            match self.policy {
+                SlowClientPolicy::DropOldest | SlowClientPolicy::Coalesce => {
+                    if !inner.overflowed {
+                        inner.overflowed = true;
+                        tracing::warn!(
+                            "connection {} can't keep up, dropping the events it falls behind on",
+                            self.connection
+                        );
+                    }
+                    inner.events.pop_front();
+                    self.metrics.unqueued(1);
+                    self.metrics.dropped();
+                }
+                SlowClientPolicy::Disconnect => {
+                    inner.closed = true;
+                    drop(inner);
+
+                    self.metrics.disconnected();
+                    self.ready.notify_one();
+                    return Err(Overflowed);
+                }
+            }
+        }
+
+        inner.events.push_back(event);
+        self.metrics.queued(inner.events.len());
+        drop(inner);
+
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    /// Lets the client have what's already queued, after which [`ClientQueue::pop`] gives up and
+    /// the connection is closed with `close_frame`.
+    pub(super) fn drain(&self, close_frame: CloseFrame) {
+        self.lock().close_frame.get_or_insert(close_frame);
+        self.ready.notify_one();
+    }
+
+    pub(super) fn is_draining(&self) -> bool {
+        self.lock().close_frame.is_some()
+    }
+
+    pub(super) fn close_frame(&self) -> Option<CloseFrame> {
+        self.lock().close_frame.clone()
+    }
+
+    /// Waits for the next event, `None` once the queue has been closed or drained.
+    pub(super) async fn pop(&self) -> Option<ToClientEvent> {
+        loop {
+            {
+                let mut inner = self.lock();
+                if inner.closed {
+                    return None;
+                }
+                if let Some(event) = inner.events.pop_front() {
+                    self.metrics.unqueued(1);
+                    return Some(event);
+                }
+                if inner.close_frame.is_some() {
+                    return None;
+                }
+            }
+
+            // NOTE: a push between unlocking and here leaves a permit, so this can't miss it
+            self.ready.notified().await;
+        }
+    }
+}
+
+impl Drop for ClientQueue {
+    fn drop(&mut self) {
+        self.metrics.unqueued(self.lock().events.len());
+    }
+}
+
+/// Folds `update` into `queued` if both update the same state.
+///
+/// Keyed updates (see [`MultipleValueUpdate`](crate::state::MultipleValueUpdate)) only touch
+/// their own entries, so merging in the newer entries ends up with the same values. Anything
+/// else replaces the whole state, so the newer update wins.
+fn coalesce(queued: &mut ToClientEvent, update: &ToClientEvent) -> bool {
+    let (ToClientEvent::Custom { event: queued }, ToClientEvent::Custom { event: update }) =
+        (queued, update)
+    else {
+        return false;
+    };
+    let (Some(queued_of), Some(update_of)) = (state_update(queued), state_update(update)) else {
+        return false;
+    };
+    if queued_of != update_of {
+        return false;
+    }
+
+    let (_, keyed) = update_of;
+    if !keyed {
+        *queued = update.clone();
+        return true;
+    }
+
+    let (Some(queued), Some(update)) = (
+        queued.get_mut("event").and_then(Value::as_array_mut),
+        update.get("event").and_then(Value::as_array),
+    ) else {
+        return false;
+    };
+
+    // NOTE: entries for keys that are already queued replace them, so a client that keeps
+    // falling behind doesn't grow the update forever
+    let mut positions: HashMap<String, usize> = queued
+        .iter()
+        .enumerate()
+        .filter_map(|(at, entry)| Some((entry.get(0)?.to_string(), at)))
+        .collect();
+    for entry in update {
+        match entry.get(0).and_then(|key| positions.get(&key.to_string())) {
+            Some(&at) => queued[at] = entry.clone(),
+            None => {
+                if let Some(key) = entry.get(0) {
+                    positions.insert(key.to_string(), queued.len());
+                }
+                queued.push(entry.clone());
+            }
+        }
+    }
+
+    true
+}
+
+/// The state key of a [`Stateful`](crate::state::Stateful) update, and whether it's keyed.
+fn state_update(event: &Value) -> Option<(&str, bool)> {
+    let state_key = event.get("state_key")?.as_str()?;
+    let keyed = event
+        .get("keyed")
+        .and_then(Value::as_bool)
+        .unwrap_or_default();
+
+    Some((state_key, keyed))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::ws::close_code;
+    use serde_json::json;
+
+    use super::*;
+
+    fn alert(msg: &str) -> ToClientEvent {
+        ToClientEvent::Alert {
+            msg: msg.to_string(),
+        }
+    }
+
+    fn update(state_key: &str, keyed: bool, event: Value) -> ToClientEvent {
+        ToClientEvent::Custom {
+            event: json!({ "state_key": state_key, "keyed": keyed, "event": event }),
+        }
+    }
+
+    fn queued(queue: &ClientQueue) -> Vec<Value> {
+        queue
+            .lock()
+            .events
+            .iter()
+            .map(|event| match event {
+                ToClientEvent::Alert { msg } => json!(msg),
+                ToClientEvent::Custom { event } => event["event"].clone(),
+                event => panic!("unexpected {event:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drop_oldest_makes_room() {
+        let metrics = Metrics::default();
+        let queue = ClientQueue::new(
+            ConnectionId::next(),
+            2,
+            SlowClientPolicy::DropOldest,
+            metrics.clone(),
+        );
+
+        for msg in ["a", "b", "c"] {
+            assert!(queue.push(alert(msg)).is_ok());
+        }
+
+        assert_eq!(queued(&queue), [json!("b"), json!("c")]);
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.dropped, 1);
+        assert_eq!(snapshot.queued, 2);
+    }
+
+    #[test]
+    fn coalesce_waits_until_full() {
+        let metrics = Metrics::default();
+        let queue = ClientQueue::new(
+            ConnectionId::next(),
+            10,
+            SlowClientPolicy::Coalesce,
+            metrics.clone(),
+        );
+
+        queue.push(update("count", false, json!(1))).unwrap();
+        queue.push(update("count", false, json!(2))).unwrap();
+
+        assert_eq!(queued(&queue), [json!(1), json!(2)]);
+        assert_eq!(metrics.snapshot().coalesced, 0);
+    }
+
+    #[test]
+    fn coalesce_replaces_whole_updates() {
+        let metrics = Metrics::default();
+        let queue = ClientQueue::new(
+            ConnectionId::next(),
+            3,
+            SlowClientPolicy::Coalesce,
+            metrics.clone(),
+        );
+
+        queue.push(update("count", false, json!(1))).unwrap();
+        queue.push(alert("hi")).unwrap();
+        queue.push(update("name", false, json!("pserve"))).unwrap();
+        queue.push(update("count", false, json!(2))).unwrap();
+
+        assert_eq!(queued(&queue), [json!(2), json!("hi"), json!("pserve")]);
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.coalesced, 1);
+        assert_eq!(snapshot.dropped, 0);
+    }
+
+    #[test]
+    fn coalesce_merges_keyed_updates() {
+        let queue = ClientQueue::new(
+            ConnectionId::next(),
+            2,
+            SlowClientPolicy::Coalesce,
+            Metrics::default(),
+        );
+
+        queue
+            .push(update("boxes", true, json!([[0, true], [1, true]])))
+            .unwrap();
+        queue.push(alert("hi")).unwrap();
+        queue
+            .push(update("boxes", true, json!([[1, false], [2, true]])))
+            .unwrap();
+
+        assert_eq!(
+            queued(&queue),
+            [json!([[0, true], [1, false], [2, true]]), json!("hi")]
+        );
+        // NOTE: a whole update and a keyed one for the same state don't mix
+        queue.push(update("boxes", false, json!([true]))).unwrap();
+
+        assert_eq!(queued(&queue), [json!("hi"), json!([true])]);
+    }
+
+    #[test]
+    fn coalesce_still_drops_when_full() {
+        let queue = ClientQueue::new(
+            ConnectionId::next(),
+            2,
+            SlowClientPolicy::Coalesce,
+            Metrics::default(),
+        );
+
+        for msg in ["a", "b", "c"] {
+            queue.push(alert(msg)).unwrap();
+        }
+
+        assert_eq!(queued(&queue), [json!("b"), json!("c")]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_the_queue() {
+        let metrics = Metrics::default();
+        let queue = ClientQueue::new(
+            ConnectionId::next(),
+            2,
+            SlowClientPolicy::Disconnect,
+            metrics.clone(),
+        );
+
+        queue.push(alert("a")).unwrap();
+        queue.push(alert("b")).unwrap();
+        assert!(queue.push(alert("c")).is_err());
+        assert!(queue.push(alert("d")).is_err());
+
+        assert!(queue.pop().await.is_none());
+        assert_eq!(metrics.snapshot().disconnected, 1);
+    }
+
+    #[tokio::test]
+    async fn drain_delivers_what_is_queued() {
+        let queue = ClientQueue::new(
+            ConnectionId::next(),
+            10,
+            SlowClientPolicy::DropOldest,
+            Metrics::default(),
+        );
+
+        queue.push(alert("a")).unwrap();
+        queue.drain(CloseFrame {
+            code: close_code::AWAY,
+            reason: "bye".into(),
+        });
+
+        assert!(matches!(queue.pop().await, Some(ToClientEvent::Alert { msg }) if msg == "a"));
+        assert!(queue.pop().await.is_none());
+        assert_eq!(
+            queue.close_frame().map(|frame| frame.code),
+            Some(close_code::AWAY)
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use futures_util::future::BoxFuture;
+use serde_json::Value;
+
+use super::{Ctx, EventError, StateHandle};
+use crate::protocol::Rpc;
+
+pub(super) type RpcProcessor<T> =
+    Box<dyn Fn(&mut T, Ctx, Value) -> Result<Value, EventError> + Send + Sync>;
+pub(super) type AsyncRpcProcessor<T> = Box<
+    dyn Fn(
+            StateHandle<T>,
+            Ctx,
+            Value,
+        ) -> Result<BoxFuture<'static, Result<Value, EventError>>, EventError>
+        + Send
+        + Sync,
+>;
+
+pub(super) enum RpcHandler<T> {
+    Sync(RpcProcessor<T>),
+    Async(AsyncRpcProcessor<T>),
+}
+
+pub(super) fn unknown_method(method: &str) -> EventError {
+    EventError::new(
+        "unknown_method",
+        format!("no rpc handler registered for `{method}`"),
+    )
+}
+
+pub(super) fn decode_request<R: Rpc>(payload: Value) -> Result<R, EventError> {
+    serde_json::from_value(payload).map_err(|err| {
+        EventError::new(
+            "invalid_request",
+            format!("failed to deserialize `{}`: {err}", R::method()),
+        )
+    })
+}
+
+pub(super) fn encode_response<R: Rpc>(response: R::Response) -> Result<Value, EventError> {
+    // NOTE: the handler did its job, but the client is still owed an answer
+    serde_json::to_value(response).map_err(|err| {
+        EventError::new(
+            "invalid_response",
+            format!(
+                "failed to serialize the response to `{}`: {err}",
+                R::method()
+            ),
+        )
+    })
+}
@@ -0,0 +1,208 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use sha1::{Digest, Sha1};
+
+pub(super) const SESSION_COOKIE: &str = "pserve_session";
+
+/// The secret behind a [`SessionId`], only ever sent back to the browser in the
+/// `pserve_session` cookie.
+///
+/// Issued by the server the first time a browser opens a websocket and kept in the cookie
+/// from then on. Anyone holding it can act as that browser, so it's never logged or handed
+/// to processors, see [`SessionToken::id`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(super) struct SessionToken(u128);
+
+impl SessionToken {
+    pub(super) fn generate() -> Self {
+        let mut bytes = [0; 16];
+        getrandom::fill(&mut bytes).expect("failed to generate a session token");
+
+        Self(u128::from_le_bytes(bytes))
+    }
+
+    /// `None` for anything this server couldn't have issued.
+    pub(super) fn from_cookie(value: &str) -> Option<Self> {
+        if value.len() != 32 || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        u128::from_str_radix(value, 16).ok().map(Self)
+    }
+
+    /// The `Set-Cookie` value that keeps the browser on this session.
+    pub(super) fn cookie(&self, path: &str) -> String {
+        format!(
+            "{SESSION_COOKIE}={:032x}; Path={path}; HttpOnly; SameSite=Lax",
+            self.0
+        )
+    }
+
+    /// The same token always hashes to the same id, and the id can't be turned back into
+    /// the token.
+    pub(super) fn id(&self) -> SessionId {
+        let digest = Sha1::digest(self.0.to_le_bytes());
+
+        SessionId(u64::from_le_bytes(digest[..8].try_into().unwrap()))
+    }
+}
+
+impl fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SessionToken({})", self.id())
+    }
+}
+
+/// Identifies a browser across reconnects, reloads and tabs.
+///
+/// Safe to log, store or show to other clients, unlike the cookie it's derived from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SessionId(u64);
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidSessionId;
+
+impl fmt::Display for InvalidSessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "session ids are 16 hex digits")
+    }
+}
+
+impl std::error::Error for InvalidSessionId {}
+
+impl FromStr for SessionId {
+    type Err = InvalidSessionId;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 16 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(InvalidSessionId);
+        }
+
+        u64::from_str_radix(s, 16)
+            .map(Self)
+            .map_err(|_| InvalidSessionId)
+    }
+}
+
+impl Serialize for SessionId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SessionId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// A single websocket, one [`SessionId`] can have any number of these open at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    pub(super) fn next() -> Self {
+        static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+        Self(NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "0123456789abcdef0123456789ABCDEF";
+
+    #[test]
+    fn only_32_hex_digit_cookies_are_tokens() {
+        assert!(SessionToken::from_cookie(TOKEN).is_some());
+
+        for cookie in [
+            "",
+            "0123456789abcdef",
+            "0123456789abcdef0123456789abcdef0",
+            "0123456789abcdef0123456789abcdeg",
+            "+123456789abcdef0123456789abcdef",
+            "0123456789abcdef 123456789abcdef",
+        ] {
+            assert_eq!(SessionToken::from_cookie(cookie), None, "{cookie:?}");
+        }
+    }
+
+    #[test]
+    fn cookies_round_trip() {
+        let token = SessionToken::generate();
+        let cookie = token.cookie("/");
+        let value = cookie
+            .strip_prefix("pserve_session=")
+            .and_then(|cookie| cookie.split(';').next())
+            .unwrap();
+
+        assert_eq!(SessionToken::from_cookie(value), Some(token));
+    }
+
+    #[test]
+    fn same_token_same_id() {
+        let token = SessionToken::from_cookie(TOKEN).unwrap();
+
+        assert_eq!(token.id(), SessionToken::from_cookie(TOKEN).unwrap().id());
+        assert_ne!(token.id(), SessionToken::generate().id());
+    }
+
+    #[test]
+    fn ids_dont_leak_the_token() {
+        let token = SessionToken::from_cookie(TOKEN).unwrap();
+
+        assert!(
+            !format!("{token:?}")
+                .to_lowercase()
+                .contains(&TOKEN.to_lowercase())
+        );
+        assert!(!token.id().to_string().contains(&TOKEN.to_lowercase()[..16]));
+    }
+
+    #[test]
+    fn ids_round_trip() {
+        let id = SessionToken::generate().id();
+
+        assert_eq!(id.to_string().parse::<SessionId>().unwrap(), id);
+
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{id}\""));
+        assert_eq!(serde_json::from_str::<SessionId>(&json).unwrap(), id);
+    }
+
+    #[test]
+    fn malformed_ids_are_rejected() {
+        for id in [
+            "",
+            "0123456789abcde",
+            "0123456789abcdef0",
+            "0123456789abcdeg",
+        ] {
+            assert!(id.parse::<SessionId>().is_err(), "{id:?}");
+        }
+        assert!(serde_json::from_str::<SessionId>("\"nope\"").is_err());
+        assert!(serde_json::from_str::<SessionId>("42").is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use std::borrow::Cow;
+
+use super::Heartbeat;
+use crate::protocol::WireFormat;
+
+/// The html page every route is served in, see [`App::shell`](super::App::shell).
+///
+/// Templates can use these placeholders:
+/// - `{{title}}`: the page title, escaped
+/// - `{{head}}`: extra tags for the `<head>`, e.g. stylesheets or meta tags, added to the end
+///   of the `<head>` if it's missing
+/// - `{{loading}}`: shown until the wasm blob has loaded
+/// - `{{runtime}}`: pserve's script, added to the end of the `<head>` if it's missing
+/// - `{{mount}}`: where components are rendered, added to the end of the `<body>` if it's
+///   missing
+///
+/// Elements with a `data-pserve-status` attribute get the connection status as their text.
+#[derive(Debug, Clone)]
+pub struct Shell {
+    template: Cow<'static, str>,
+    title: String,
+    head: String,
+    loading: String,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new(include_str!("../html/index.html"))
+    }
+}
+
+impl Shell {
+    pub fn new(template: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            template: template.into(),
+            title: "pserve".to_string(),
+            head: String::new(),
+            loading: "<p>Loading wasm blob</p>".to_string(),
+        }
+    }
+
+    pub fn title(mut self, title: &str) -> Self {
+        self.title = title.to_string();
+        self
+    }
+
+    /// Raw html added to the `<head>`, can be called any number of times.
+    pub fn head(mut self, tags: &str) -> Self {
+        self.head.push_str(tags);
+        self
+    }
+
+    /// Raw html shown until the wasm blob has loaded.
+    pub fn loading(mut self, html: &str) -> Self {
+        self.loading = html.to_string();
+        self
+    }
+
+    /// Fills in everything but `{{mount}}`, which changes with every request.
+    pub(super) fn render(
+        &self,
+        base_path: &str,
+        wasm_url: &str,
+        format: WireFormat,
+        heartbeat: Heartbeat,
+    ) -> String {
+        let runtime = format!(
+            "<script type=\"text/javascript\">\n{}</script>",
+            include_str!("../html/runtime.js")
+                .replace("{{base_path}}", &script_string(base_path))
+                .replace("{{wasm_url}}", &script_string(wasm_url))
+                .replace("{{wire_protocol}}", &script_string(format.protocol()))
+                .replace(
+                    "{{heartbeat_interval}}",
+                    &heartbeat.interval.as_millis().to_string()
+                )
+                .replace(
+                    "{{heartbeat_timeout}}",
+                    &heartbeat.timeout.as_millis().to_string()
+                )
+        );
+
+        let mut html = self.template.to_string();
+        if !html.contains("{{head}}") {
+            insert_before(&mut html, "</head>", "{{head}}");
+        }
+        if !html.contains("{{runtime}}") {
+            insert_before(&mut html, "</head>", "{{runtime}}");
+        }
+        if !html.contains("{{mount}}") {
+            insert_before(&mut html, "</body>", "{{mount}}");
+        }
+
+        fill(
+            &html,
+            &[
+                ("title", &escape_html(&self.title)),
+                ("head", &self.head),
+                (
+                    "loading",
+                    &format!("<div data-pserve-loading>{}</div>", self.loading),
+                ),
+                ("runtime", &runtime),
+                ("mount", "{{mount}}"),
+            ],
+        )
+    }
+}
+
+/// Replaces every `{{name}}` in `template` in one go, so placeholders in what's filled in (e.g. a
+/// title of `{{runtime}}`) are left alone.
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut html = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        html.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let value = rest[2..].find("}}").and_then(|end| {
+            let name = &rest[2..2 + end];
+            let (_, value) = values
+                .iter()
+                .find(|(placeholder, _)| *placeholder == name)?;
+            Some((value, end + 4))
+        });
+        match value {
+            Some((value, len)) => {
+                html.push_str(value);
+                rest = &rest[len..];
+            }
+            None => {
+                html.push_str("{{");
+                rest = &rest[2..];
+            }
+        }
+    }
+
+    html.push_str(rest);
+    html
+}
+
+/// A JS string literal that's safe to put in a `<script>`.
+fn script_string(value: &str) -> String {
+    // NOTE: `<` is escaped so a weird prefix can't close the script tag
+    serde_json::to_string(value)
+        .unwrap()
+        .replace('<', "\\u003c")
+}
+
+fn insert_before(html: &mut String, tag: &str, insert: &str) {
+    match html.rfind(tag) {
+        Some(at) => html.insert_str(at, insert),
+        None => html.push_str(insert),
+    }
+}
+
+pub(super) fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(shell: &Shell) -> String {
+        shell.render("", "/client.wasm", WireFormat::Json, Heartbeat::default())
+    }
+
+    fn runtimes(html: &str) -> usize {
+        html.matches("const PSERVE_BASE_PATH").count()
+    }
+
+    #[test]
+    fn titles_are_escaped() {
+        let html = render(&Shell::default().title("<script>alert(\"&\")</script>"));
+
+        assert!(
+            html.contains("<title>&lt;script&gt;alert(&quot;&amp;&quot;)&lt;/script&gt;</title>")
+        );
+    }
+
+    #[test]
+    fn placeholders_in_values_are_left_alone() {
+        let html = render(&Shell::default().title("{{runtime}}"));
+
+        assert!(html.contains("<title>{{runtime}}</title>"));
+        assert_eq!(runtimes(&html), 1);
+    }
+
+    #[test]
+    fn head_goes_in_the_head() {
+        let tag = r#"<link rel="stylesheet" href="/static/style.css">"#;
+
+        for template in [
+            include_str!("../html/index.html"),
+            "<html><head><title>{{title}}</title></head><body></body></html>",
+        ] {
+            let html = render(&Shell::new(template).head(tag));
+            let head = html.find(tag).expect("head is missing");
+
+            assert!(head < html.find("</head>").unwrap(), "{html}");
+        }
+    }
+
+    #[test]
+    fn loading_is_marked_for_the_runtime() {
+        let html = render(&Shell::default().loading("<p>hold on</p>"));
+
+        assert!(html.contains("<div data-pserve-loading><p>hold on</p></div>"));
+    }
+
+    #[test]
+    fn runtime_is_added_once() {
+        let default = render(&Shell::default());
+        assert_eq!(runtimes(&default), 1);
+
+        let bare = render(&Shell::new("<html><head></head><body></body></html>"));
+        assert_eq!(runtimes(&bare), 1);
+        assert!(bare.find("const PSERVE_BASE_PATH").unwrap() < bare.find("</head>").unwrap());
+        assert!(bare.contains("{{mount}}</body>"));
+    }
+}
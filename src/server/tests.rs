@@ -0,0 +1,710 @@
+//! Whole servers on a random port, talked to over a real websocket.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{Notify, oneshot},
+    task::JoinHandle,
+};
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream,
+    tungstenite::{
+        Message as WsMessage,
+        client::IntoClientRequest,
+        handshake::client::Response,
+        http::{HeaderName, header::COOKIE},
+    },
+};
+
+use super::*;
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+struct Server<T> {
+    addr: SocketAddr,
+    state: Arc<ApiState<T>>,
+    shutdown: oneshot::Sender<()>,
+    served: JoinHandle<Result<T, Box<dyn std::error::Error + Send + Sync>>>,
+}
+
+async fn serve<T: Default + Send + Sync + 'static>(mut app: App<T>) -> Server<T> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (serving, state) = oneshot::channel();
+    app.serving = Some(serving);
+    let (shutdown, signal) = oneshot::channel();
+    let served = tokio::spawn(app.wasm(b"").listener(listener).serve_with_shutdown(async {
+        let _ = signal.await;
+    }));
+
+    Server {
+        addr,
+        state: state.await.unwrap(),
+        shutdown,
+        served,
+    }
+}
+
+impl<T> Server<T> {
+    async fn connect(&self) -> Client {
+        self.connect_with(&[]).await.0
+    }
+
+    async fn connect_with(&self, headers: &[(HeaderName, &str)]) -> (Client, Response) {
+        let mut request = format!("ws://{}/ws", self.addr)
+            .into_client_request()
+            .unwrap();
+        for (name, value) in headers {
+            request.headers_mut().insert(name, value.parse().unwrap());
+        }
+
+        tokio_tungstenite::connect_async(request).await.unwrap()
+    }
+
+    async fn stop(self) -> T {
+        self.shutdown.send(()).unwrap();
+        self.served.await.unwrap().unwrap()
+    }
+}
+
+async fn send(client: &mut Client, event: Value) {
+    client
+        .send(WsMessage::Text(event.to_string().into()))
+        .await
+        .unwrap();
+}
+
+/// The `name=value` part of the session cookie the server set.
+fn cookie_of(response: &Response) -> String {
+    response.headers()[SET_COOKIE]
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_string()
+}
+
+/// The next event that isn't a heartbeat, `None` once the server closed the connection.
+async fn next_event(client: &mut Client) -> Option<Value> {
+    loop {
+        let msg = tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("timed out waiting for an event")?;
+
+        match msg {
+            Ok(WsMessage::Text(text)) => {
+                let event: Value = serde_json::from_str(&text).unwrap();
+                if event["type"] != "heartbeat" {
+                    return Some(event);
+                }
+            }
+            Ok(WsMessage::Close(_)) | Err(_) => return None,
+            Ok(_) => {}
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counter {
+    total: u32,
+}
+
+#[derive(Deserialize)]
+enum CounterEvent {
+    Add { n: u32 },
+}
+
+#[tokio::test]
+async fn shutdown_waits_for_async_processors() {
+    let started = Arc::new(Notify::new());
+    let app = App::<Counter>::default().on_async({
+        let started = started.clone();
+        move |state: StateHandle<Counter>, _ctx: Ctx, CounterEvent::Add { n }| {
+            let started = started.clone();
+            async move {
+                started.notify_one();
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                state.update(|counter| counter.total += n).await;
+
+                Some(Event::ToAllClients(ToClientEvent::Alert {
+                    msg: format!("added {n}"),
+                }))
+            }
+        }
+    });
+
+    let server = serve(app).await;
+    let mut client = server.connect().await;
+    send(&mut client, json!({ "Add": { "n": 2 } })).await;
+    started.notified().await;
+
+    let stopped = tokio::spawn(server.stop());
+    assert_eq!(
+        next_event(&mut client).await,
+        Some(json!({ "type": "alert", "msg": "added 2" }))
+    );
+    assert_eq!(next_event(&mut client).await, None);
+
+    assert_eq!(stopped.await.unwrap().total, 2);
+}
+
+#[tokio::test]
+async fn heartbeats_are_text_on_msgpack_connections() {
+    let app = App::<Counter>::default()
+        .wire_format(WireFormat::MessagePack)
+        .heartbeat(Duration::from_millis(50), Duration::from_secs(5));
+    let server = serve(app).await;
+
+    let (mut client, _) = server
+        .connect_with(&[(SEC_WEBSOCKET_PROTOCOL, WireFormat::MessagePack.protocol())])
+        .await;
+
+    let msg = tokio::time::timeout(Duration::from_secs(5), client.next())
+        .await
+        .expect("timed out waiting for a heartbeat")
+        .unwrap()
+        .unwrap();
+    match msg {
+        WsMessage::Text(text) => {
+            let event: Value = serde_json::from_str(&text).unwrap();
+            assert_eq!(event, json!({ "type": "heartbeat" }));
+        }
+        msg => panic!("expected a text heartbeat, got {msg:?}"),
+    }
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn zero_persist_intervals_are_refused() {
+    let err = App::<u32>::default()
+        .wasm(b"")
+        .persist(
+            std::env::temp_dir().join("pserve-zero.json"),
+            Duration::ZERO,
+        )
+        .serve_with_shutdown(async {})
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(ConfigError::ZeroInterval {
+            setting: "App::persist"
+        })
+    ));
+}
+
+#[tokio::test]
+async fn zero_heartbeat_intervals_are_refused() {
+    let err = App::<u32>::default()
+        .wasm(b"")
+        .heartbeat(Duration::ZERO, Duration::from_secs(5))
+        .serve_with_shutdown(async {})
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(ConfigError::ZeroInterval {
+            setting: "App::heartbeat"
+        })
+    ));
+}
+
+#[tokio::test]
+async fn zero_frame_sizes_are_refused() {
+    let err = App::<u32>::default()
+        .wasm(b"")
+        .max_frame_size(0)
+        .serve_with_shutdown(async {})
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(ConfigError::ZeroFrameSize)
+    ));
+}
+
+#[tokio::test]
+async fn heartbeat_timeouts_shorter_than_the_interval_are_refused() {
+    for timeout in [Duration::from_secs(5), Duration::from_secs(10)] {
+        let err = App::<u32>::default()
+            .wasm(b"")
+            .heartbeat(Duration::from_secs(10), timeout)
+            .serve_with_shutdown(async {})
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ConfigError::HeartbeatTimeout { timeout: refused, .. }) if *refused == timeout
+        ));
+    }
+}
+
+#[tokio::test]
+async fn invalid_routes_are_refused() {
+    for route in ["/memes/{id", "memes"] {
+        let err = App::<u32>::default()
+            .wasm(b"")
+            .route(route, "Meme")
+            .serve_with_shutdown(async {})
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ConfigError::InvalidRoute { path, .. }) if path == route
+        ));
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum InternallyTagged {
+    Reset,
+}
+
+#[tokio::test]
+async fn untagged_events_are_refused() {
+    let err = App::<u32>::default()
+        .wasm(b"")
+        .on(|count, _, InternallyTagged::Reset| {
+            *count = 0;
+            None
+        })
+        .serve_with_shutdown(async {})
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(ConfigError::UntaggedEvent { event }) if event.ends_with("InternallyTagged")
+    ));
+}
+
+fn greet(_: &mut Counter, ctx: Ctx) -> Option<Event> {
+    Some(Event::ToConnection {
+        connection: ctx.connection(),
+        event: ToClientEvent::Alert {
+            msg: ctx.who().to_string(),
+        },
+    })
+}
+
+#[tokio::test]
+async fn reconnecting_with_the_cookie_keeps_the_session() {
+    let server = serve(App::<Counter>::default().on_connect(greet)).await;
+
+    let (mut first, response) = server.connect_with(&[]).await;
+    let cookie = cookie_of(&response);
+    let who = next_event(&mut first).await.unwrap()["msg"].clone();
+    first.close(None).await.unwrap();
+
+    let (mut again, _) = server.connect_with(&[(COOKIE, &cookie)]).await;
+    assert_eq!(next_event(&mut again).await.unwrap()["msg"], who);
+
+    let mut stranger = server.connect().await;
+    assert_ne!(next_event(&mut stranger).await.unwrap()["msg"], who);
+
+    let (mut forged, _) = server
+        .connect_with(&[(COOKIE, "pserve_session=not-a-token")])
+        .await;
+    assert_ne!(next_event(&mut forged).await.unwrap()["msg"], who);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn base_paths_that_cant_be_cookie_paths_are_refused() {
+    let err = App::<u32>::default()
+        .wasm(b"")
+        .base_path("/my app")
+        .serve_with_shutdown(async {})
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(ConfigError::InvalidBasePath { base_path }) if base_path == "/my app"
+    ));
+}
+
+/// Waits for `check` to pass, for things the server does on its own time like cleaning up
+/// after a closed connection.
+async fn eventually<T>(state: &ApiState<T>, check: impl AsyncFn(&ApiState<T>) -> bool) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !check(state).await {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("timed out waiting for the server");
+}
+
+#[derive(Deserialize)]
+enum RoomEvent {
+    Join { room: String },
+    Leave { room: String },
+    Say { room: String, msg: String },
+}
+
+fn rooms(_: &mut Counter, ctx: Ctx, event: RoomEvent) -> Option<Event> {
+    match event {
+        // NOTE: replying once it's done lets the test wait for it
+        RoomEvent::Join { room } => {
+            ctx.join(&room);
+            ctx.reply(ToClientEvent::Alert {
+                msg: format!("joined {room}"),
+            });
+            None
+        }
+        RoomEvent::Leave { room } => {
+            ctx.leave(&room);
+            ctx.reply(ToClientEvent::Alert {
+                msg: format!("left {room}"),
+            });
+            None
+        }
+        RoomEvent::Say { room, msg } => Some(Event::ToRoom {
+            room,
+            event: ToClientEvent::Alert { msg },
+        }),
+    }
+}
+
+fn alert(msg: &str) -> Option<Value> {
+    Some(json!({ "type": "alert", "msg": msg }))
+}
+
+#[tokio::test]
+async fn rooms_only_reach_their_members() {
+    let server = serve(App::<Counter>::default().on(rooms)).await;
+    let mut member = server.connect().await;
+    let mut outsider = server.connect().await;
+
+    // NOTE: everything the outsider says is handled in order, so once something said in the
+    // lobby arrives anything said before it would've arrived too
+    for (client, rooms) in [
+        (&mut member, ["lobby", "cats"]),
+        (&mut outsider, ["lobby", "dogs"]),
+    ] {
+        for room in rooms {
+            send(client, json!({ "Join": { "room": room } })).await;
+            assert_eq!(next_event(client).await, alert(&format!("joined {room}")));
+        }
+    }
+
+    send(
+        &mut outsider,
+        json!({ "Say": { "room": "cats", "msg": "meow" } }),
+    )
+    .await;
+    send(
+        &mut outsider,
+        json!({ "Say": { "room": "birds", "msg": "tweet" } }),
+    )
+    .await;
+    send(
+        &mut outsider,
+        json!({ "Say": { "room": "lobby", "msg": "hi" } }),
+    )
+    .await;
+    assert_eq!(next_event(&mut member).await, alert("meow"));
+    assert_eq!(next_event(&mut member).await, alert("hi"));
+    assert_eq!(next_event(&mut outsider).await, alert("hi"));
+
+    send(&mut member, json!({ "Leave": { "room": "cats" } })).await;
+    assert_eq!(next_event(&mut member).await, alert("left cats"));
+
+    send(
+        &mut outsider,
+        json!({ "Say": { "room": "cats", "msg": "meow?" } }),
+    )
+    .await;
+    send(
+        &mut outsider,
+        json!({ "Say": { "room": "lobby", "msg": "bye" } }),
+    )
+    .await;
+    assert_eq!(next_event(&mut member).await, alert("bye"));
+    assert_eq!(next_event(&mut outsider).await, alert("bye"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn closed_connections_leave_their_rooms() {
+    let server = serve(App::<Counter>::default().on(rooms)).await;
+    let mut client = server.connect().await;
+
+    send(&mut client, json!({ "Join": { "room": "cats" } })).await;
+    assert_eq!(next_event(&mut client).await, alert("joined cats"));
+    assert_eq!(server.state.rooms.read().await["cats"].len(), 1);
+
+    client.close(None).await.unwrap();
+    eventually(&server.state, async |state| {
+        state.rooms.read().await.is_empty()
+    })
+    .await;
+
+    server.stop().await;
+}
+
+#[derive(Serialize, Deserialize)]
+struct Double {
+    n: u32,
+}
+
+impl Rpc for Double {
+    type Response = u32;
+
+    fn method() -> &'static str {
+        "double"
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Halve {
+    n: u32,
+}
+
+impl Rpc for Halve {
+    type Response = u32;
+
+    fn method() -> &'static str {
+        "halve"
+    }
+}
+
+fn double(_: &mut Counter, _: Ctx, Double { n }: Double) -> Result<u32, EventError> {
+    n.checked_mul(2)
+        .ok_or_else(|| EventError::new("too_big", format!("{n} can't be doubled")))
+}
+
+fn request(id: u32, method: &str, payload: Value) -> Value {
+    json!({ "type": "request", "id": id, "method": method, "payload": payload })
+}
+
+fn error(code: &str, message: &str, correlation: &str) -> Option<Value> {
+    Some(json!({ "type": "error", "code": code, "message": message, "correlation": correlation }))
+}
+
+#[tokio::test]
+async fn rpc_requests_are_answered_to_whoever_asked() {
+    let app = App::<Counter>::default()
+        .rpc(double)
+        .rpc_async(|_, _, Halve { n }| async move { Ok(n / 2) });
+    let server = serve(app).await;
+    let mut caller = server.connect().await;
+    let mut bystander = server.connect().await;
+
+    send(&mut caller, request(1, "double", json!({ "n": 21 }))).await;
+    assert_eq!(
+        next_event(&mut caller).await,
+        Some(json!({ "type": "response", "id": 1, "payload": 42 }))
+    );
+
+    send(&mut caller, request(2, "halve", json!({ "n": 42 }))).await;
+    assert_eq!(
+        next_event(&mut caller).await,
+        Some(json!({ "type": "response", "id": 2, "payload": 21 }))
+    );
+
+    send(&mut caller, request(3, "triple", json!({ "n": 1 }))).await;
+    assert_eq!(
+        next_event(&mut caller).await,
+        error(
+            "unknown_method",
+            "no rpc handler registered for `triple`",
+            "3"
+        )
+    );
+
+    send(&mut caller, request(4, "double", json!({ "n": u32::MAX }))).await;
+    assert_eq!(
+        next_event(&mut caller).await,
+        error("too_big", "4294967295 can't be doubled", "4")
+    );
+
+    mark_done(&server).await;
+    assert_eq!(next_event(&mut bystander).await, alert("done"));
+
+    server.stop().await;
+}
+
+#[derive(Default)]
+struct Lifecycle {
+    log: Vec<(&'static str, SessionId, ConnectionId)>,
+}
+
+impl Lifecycle {
+    fn record(&mut self, hook: &'static str, ctx: Ctx) -> Option<Event> {
+        self.log.push((hook, ctx.who(), ctx.connection()));
+        None
+    }
+}
+
+#[tokio::test]
+async fn shutting_down_waits_for_disconnect_hooks() {
+    let app = App::<Lifecycle>::default()
+        .on_connect(|state, ctx| state.record("connect", ctx))
+        .on_disconnect(|state, ctx| state.record("disconnect", ctx))
+        .on_session_end(|state, ctx| state.record("session end", ctx));
+    let server = serve(app).await;
+    let _client = server.connect().await;
+    eventually(&server.state, async |state: &ApiState<Lifecycle>| {
+        !state.state.read().await.log.is_empty()
+    })
+    .await;
+
+    let hooks = server
+        .stop()
+        .await
+        .log
+        .into_iter()
+        .map(|(hook, ..)| hook)
+        .collect::<Vec<_>>();
+    assert_eq!(hooks, ["connect", "disconnect", "session end"]);
+}
+
+#[tokio::test]
+async fn sessions_start_with_the_first_tab_and_end_with_the_last() {
+    let app = App::<Lifecycle>::default()
+        .on_session_start(|state, ctx| state.record("session start", ctx))
+        .on_connect(|state, ctx| state.record("connect", ctx))
+        .on_disconnect(|state, ctx| state.record("disconnect", ctx))
+        .on_session_end(|state, ctx| state.record("session end", ctx));
+    let server = serve(app).await;
+    let log_has =
+        |len| async move |state: &ApiState<Lifecycle>| state.state.read().await.log.len() == len;
+
+    let (mut first, response) = server.connect_with(&[]).await;
+    eventually(&server.state, log_has(2)).await;
+    let (mut second, _) = server
+        .connect_with(&[(COOKIE, &cookie_of(&response))])
+        .await;
+    eventually(&server.state, log_has(3)).await;
+
+    let log = server.state.state.read().await.log.clone();
+    let (_, who, first_tab) = log[0];
+    let (_, _, second_tab) = log[2];
+    assert_ne!(first_tab, second_tab);
+    assert_eq!(
+        log,
+        [
+            ("session start", who, first_tab),
+            ("connect", who, first_tab),
+            ("connect", who, second_tab),
+        ]
+    );
+
+    first.close(None).await.unwrap();
+    eventually(&server.state, log_has(4)).await;
+    assert_eq!(
+        server.state.state.read().await.log[3],
+        ("disconnect", who, first_tab)
+    );
+
+    second.close(None).await.unwrap();
+    eventually(&server.state, log_has(6)).await;
+    assert_eq!(
+        server.state.state.read().await.log[4..],
+        [
+            ("disconnect", who, second_tab),
+            ("session end", who, second_tab)
+        ]
+    );
+
+    server.stop().await;
+}
+
+/// Delivers a marker straight to every connection, anything that was sent to them before it
+/// arrives first.
+async fn mark_done<T: Send + Sync>(server: &Server<T>) {
+    server
+        .state
+        .deliver(
+            ToClientEvent::Alert {
+                msg: "done".to_string(),
+            },
+            |_, _| true,
+        )
+        .await;
+}
+
+fn alert_request(msg: &str) -> Value {
+    json!({ "type": "alert", "msg": msg })
+}
+
+#[tokio::test]
+async fn alerts_are_rejected_without_a_processor() {
+    let server = serve(App::<Counter>::default()).await;
+    let mut sender = server.connect().await;
+    let mut bystander = server.connect().await;
+
+    send(&mut sender, alert_request("hi everyone")).await;
+    assert_eq!(
+        next_event(&mut sender).await,
+        error_without_correlation("unknown_alert", "no alert processor registered")
+    );
+
+    mark_done(&server).await;
+    assert_eq!(next_event(&mut bystander).await, alert("done"));
+
+    server.stop().await;
+}
+
+#[derive(Default)]
+struct Admins {
+    admins: Vec<SessionId>,
+}
+
+#[tokio::test]
+async fn alert_processors_decide_who_can_alert() {
+    let app = App::<Admins>::default()
+        .on_connect(|state, ctx| {
+            if state.admins.is_empty() {
+                state.admins.push(ctx.who());
+            }
+            ctx.reply(ToClientEvent::Alert {
+                msg: "welcome".to_string(),
+            });
+            None
+        })
+        .alert_processor(|state, ctx, msg| {
+            if !state.admins.contains(&ctx.who()) {
+                return Err(EventError::new("forbidden", "only admins can alert"));
+            }
+
+            Ok(Some(Event::ToAllClients(ToClientEvent::Alert { msg })))
+        });
+    let server = serve(app).await;
+    let mut admin = server.connect().await;
+    assert_eq!(next_event(&mut admin).await, alert("welcome"));
+    let mut user = server.connect().await;
+    assert_eq!(next_event(&mut user).await, alert("welcome"));
+
+    send(&mut user, alert_request("free money")).await;
+    assert_eq!(
+        next_event(&mut user).await,
+        error_without_correlation("forbidden", "only admins can alert")
+    );
+
+    send(&mut admin, alert_request("maintenance soon")).await;
+    assert_eq!(next_event(&mut admin).await, alert("maintenance soon"));
+    assert_eq!(next_event(&mut user).await, alert("maintenance soon"));
+
+    server.stop().await;
+}
+
+fn error_without_correlation(code: &str, message: &str) -> Option<Value> {
+    Some(json!({ "type": "error", "code": code, "message": message, "correlation": null }))
+}
@@ -0,0 +1,303 @@
+use std::fmt;
+
+use futures_util::future::BoxFuture;
+use serde::{
+    Deserializer,
+    de::{self, DeserializeOwned, Visitor},
+    forward_to_deserialize_any,
+};
+
+use super::{Ctx, Event, EventError, StateHandle};
+
+pub(super) type TypedProcessor<T> =
+    Box<dyn Fn(&mut T, Ctx, serde_json::Value) -> Result<Option<Event>, EventError> + Send + Sync>;
+pub(super) type AsyncTypedProcessor<T> = Box<
+    dyn Fn(
+            StateHandle<T>,
+            Ctx,
+            serde_json::Value,
+        ) -> Result<BoxFuture<'static, Result<Option<Event>, EventError>>, EventError>
+        + Send
+        + Sync,
+>;
+
+pub(super) enum TypedHandler<T> {
+    Sync(TypedProcessor<T>),
+    Async(AsyncTypedProcessor<T>),
+}
+
+#[derive(Debug)]
+pub enum DispatchError {
+    /// Nothing was registered to handle an event with this tag
+    UnknownEvent { tag: String },
+    /// A handler was registered for the tag, but the payload didn't fit its type
+    Deserialize {
+        tag: String,
+        error: serde_json::Error,
+    },
+}
+
+impl DispatchError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            DispatchError::UnknownEvent { .. } => "unknown_event",
+            DispatchError::Deserialize { .. } => "invalid_event",
+        }
+    }
+}
+
+impl From<DispatchError> for EventError {
+    fn from(err: DispatchError) -> Self {
+        EventError::new(err.code(), err.to_string())
+    }
+}
+
+impl fmt::Display for DispatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DispatchError::UnknownEvent { tag } => write!(f, "no handler registered for `{tag}`"),
+            DispatchError::Deserialize { tag, error } => {
+                write!(f, "failed to deserialize `{tag}`: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DispatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DispatchError::UnknownEvent { .. } => None,
+            DispatchError::Deserialize { error, .. } => Some(error),
+        }
+    }
+}
+
+/// The tags a typed handler is interested in, pulled out of the type's `Deserialize` impl.
+///
+/// An externally tagged enum handles every one of its variants and is deserialized from the
+/// whole event. A struct handles the variant with its own name and is deserialized from that
+/// variant's payload, so `struct AddMeme { .. }` picks up a client's `ClientEvent::AddMeme(..)`.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum EventTags {
+    Variants(&'static [&'static str]),
+    Struct(&'static str),
+}
+
+impl EventTags {
+    /// `None` if `E` has no tag to route it by, e.g. an internally tagged enum.
+    pub(super) fn of<E: DeserializeOwned>() -> Option<Self> {
+        let mut tags = None;
+        let _ = E::deserialize(TagProbe { tags: &mut tags });
+        tags
+    }
+
+    pub(super) fn tags(&self) -> Vec<&'static str> {
+        match self {
+            EventTags::Variants(variants) => variants.to_vec(),
+            EventTags::Struct(name) => vec![name],
+        }
+    }
+
+    pub(super) fn deserialize<E: DeserializeOwned>(
+        &self,
+        tag: &str,
+        value: serde_json::Value,
+    ) -> Result<E, DispatchError> {
+        let value = match (self, value) {
+            (EventTags::Variants(_), value) => value,
+            (EventTags::Struct(_), serde_json::Value::Object(mut map)) => {
+                map.remove(tag).unwrap_or_default()
+            }
+            (EventTags::Struct(_), _) => serde_json::Value::Null,
+        };
+
+        serde_json::from_value(value).map_err(|error| DispatchError::Deserialize {
+            tag: tag.to_string(),
+            error,
+        })
+    }
+}
+
+/// Finds the tag of an externally tagged event, `{"Tag": ..}` or `"Tag"` for unit variants.
+pub(super) fn event_tag(value: &serde_json::Value) -> Option<&str> {
+    match value {
+        serde_json::Value::Object(map) if map.len() == 1 => map.keys().next().map(String::as_str),
+        serde_json::Value::String(tag) => Some(tag),
+        _ => None,
+    }
+}
+
+/// A deserializer that never produces a value, it just remembers what the type asked for.
+struct TagProbe<'a> {
+    tags: &'a mut Option<EventTags>,
+}
+
+impl<'de> Deserializer<'de> for TagProbe<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("probing"))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        variants: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.tags = Some(EventTags::Variants(variants));
+        Err(de::Error::custom("probing"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        _: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.tags = Some(EventTags::Struct(name));
+        Err(de::Error::custom("probing"))
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.tags = Some(EventTags::Struct(name));
+        Err(de::Error::custom("probing"))
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.tags = Some(EventTags::Struct(name));
+        Err(de::Error::custom("probing"))
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        _: usize,
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.tags = Some(EventTags::Struct(name));
+        Err(de::Error::custom("probing"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit seq tuple map identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    enum ClientEvent {
+        AddMeme { url: String },
+        Clear,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct AddMeme {
+        url: String,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Clear;
+
+    #[derive(Deserialize)]
+    #[serde(tag = "type")]
+    #[allow(dead_code)]
+    enum InternallyTagged {
+        AddMeme { url: String },
+    }
+
+    #[test]
+    fn enums_handle_every_variant() {
+        let tags = EventTags::of::<ClientEvent>().unwrap();
+        assert_eq!(tags.tags(), ["AddMeme", "Clear"]);
+
+        let event = json!({ "AddMeme": { "url": "cat.png" } });
+        assert_eq!(
+            tags.deserialize::<ClientEvent>("AddMeme", event).unwrap(),
+            ClientEvent::AddMeme {
+                url: "cat.png".to_string()
+            }
+        );
+        assert_eq!(
+            tags.deserialize::<ClientEvent>("Clear", json!("Clear"))
+                .unwrap(),
+            ClientEvent::Clear
+        );
+    }
+
+    #[test]
+    fn structs_handle_the_variant_with_their_name() {
+        let tags = EventTags::of::<AddMeme>().unwrap();
+        assert_eq!(tags.tags(), ["AddMeme"]);
+
+        let event = json!({ "AddMeme": { "url": "cat.png" } });
+        assert_eq!(
+            tags.deserialize::<AddMeme>("AddMeme", event).unwrap(),
+            AddMeme {
+                url: "cat.png".to_string()
+            }
+        );
+
+        let tags = EventTags::of::<Clear>().unwrap();
+        assert_eq!(tags.tags(), ["Clear"]);
+        assert_eq!(
+            tags.deserialize::<Clear>("Clear", json!("Clear")).unwrap(),
+            Clear
+        );
+    }
+
+    #[test]
+    fn internally_tagged_enums_are_rejected() {
+        assert!(EventTags::of::<InternallyTagged>().is_none());
+    }
+
+    #[test]
+    fn finds_the_tag_of_externally_tagged_events() {
+        assert_eq!(
+            event_tag(&json!({ "AddMeme": { "url": "cat.png" } })),
+            Some("AddMeme")
+        );
+        assert_eq!(event_tag(&json!("Clear")), Some("Clear"));
+        assert_eq!(
+            event_tag(&json!({ "type": "AddMeme", "url": "cat.png" })),
+            None
+        );
+        assert_eq!(event_tag(&json!(42)), None);
+    }
+
+    #[test]
+    fn payloads_that_dont_fit_are_invalid_events() {
+        let err = EventTags::of::<AddMeme>()
+            .unwrap()
+            .deserialize::<AddMeme>("AddMeme", json!({ "AddMeme": { "url": 42 } }))
+            .unwrap_err();
+        assert!(matches!(&err, DispatchError::Deserialize { tag, .. } if tag == "AddMeme"));
+
+        let err = EventError::from(err);
+        assert_eq!(err.code, "invalid_event");
+    }
+
+    #[test]
+    fn unknown_events_have_their_own_code() {
+        let err = EventError::from(DispatchError::UnknownEvent {
+            tag: "Nope".to_string(),
+        });
+        assert_eq!(err.code, "unknown_event");
+        assert_eq!(err.message, "no handler registered for `Nope`");
+    }
+}
@@ -0,0 +1,237 @@
+use std::io::Write;
+
+use axum::{
+    body::Bytes,
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, VARY},
+    },
+    response::{IntoResponse, Response},
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{CacheControl, ETag, IfNoneMatch},
+};
+
+use super::assets::content_hash;
+
+/// The client's wasm blob, hashed and compressed once when the server starts.
+pub(super) struct WasmBlob {
+    hash: String,
+    raw: Bytes,
+    gzip: Bytes,
+    brotli: Bytes,
+}
+
+impl WasmBlob {
+    /// Compressing a debug build takes a few seconds, so this belongs on a blocking thread.
+    pub(super) fn new(blob: &'static [u8]) -> Self {
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        gzip.write_all(blob).unwrap();
+
+        // NOTE: quality 11 takes ages for barely any gain, 9 is plenty
+        let mut brotli = brotli::CompressorWriter::new(Vec::new(), 4096, 9, 22);
+        brotli.write_all(blob).unwrap();
+
+        Self {
+            hash: content_hash(blob),
+            raw: Bytes::from_static(blob),
+            gzip: Bytes::from(gzip.finish().unwrap()),
+            brotli: Bytes::from(brotli.into_inner()),
+        }
+    }
+
+    /// Changes whenever the blob does, so it can be cached forever.
+    pub(super) fn hashed_path(&self) -> String {
+        format!("/client.{}.wasm", self.hash)
+    }
+
+    /// `immutable` is for requests to [`WasmBlob::hashed_path`], anything else has to be
+    /// revalidated since the blob behind it changes between deploys.
+    pub(super) fn serve(
+        &self,
+        headers: &HeaderMap,
+        if_none_match: Option<IfNoneMatch>,
+        immutable: bool,
+    ) -> Response {
+        let accept_encoding = headers
+            .get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let (encoding, body) = match preferred_encoding(&accept_encoding) {
+            Some("br") => (Some("br"), &self.brotli),
+            Some("gzip") => (Some("gzip"), &self.gzip),
+            _ => (None, &self.raw),
+        };
+
+        // NOTE: every encoding is its own representation, so it gets its own etag
+        let etag: ETag = format!("\"{}-{}\"", self.hash, encoding.unwrap_or("identity"))
+            .parse()
+            .unwrap();
+        let cache_control = if immutable {
+            CacheControl::new()
+                .with_public()
+                .with_max_age(std::time::Duration::from_secs(365 * 24 * 60 * 60))
+                .with_immutable()
+        } else {
+            CacheControl::new().with_no_cache()
+        };
+
+        let mut response_headers = HeaderMap::new();
+        response_headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/wasm"));
+        if let Some(encoding) = encoding {
+            response_headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        }
+
+        // NOTE: a 304 stands in for the full response, so caches need to know what it varies by
+        let headers = (
+            TypedHeader(etag.clone()),
+            TypedHeader(cache_control),
+            [(VARY, HeaderValue::from_static("accept-encoding"))],
+        );
+        if let Some(if_none_match) = if_none_match
+            && !if_none_match.precondition_passes(&etag)
+        {
+            return (StatusCode::NOT_MODIFIED, headers).into_response();
+        }
+
+        (headers, response_headers, body.clone()).into_response()
+    }
+}
+
+/// The coding an `Accept-Encoding` header likes best out of the ones the blob is compressed
+/// with, brotli if it likes both equally. `None` means the raw blob.
+fn preferred_encoding(accept_encoding: &str) -> Option<&'static str> {
+    let mut preferred = None;
+    let mut best = 0.0;
+
+    for coding in ["br", "gzip"] {
+        let q = quality(accept_encoding, coding);
+        if q > best {
+            preferred = Some(coding);
+            best = q;
+        }
+    }
+
+    preferred
+}
+
+/// The `q` an `Accept-Encoding` header gives `coding`, `*` covers every coding it doesn't
+/// name. `0.0` means the coding isn't acceptable.
+fn quality(accept_encoding: &str, coding: &str) -> f32 {
+    let mut wildcard = None;
+
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let q = match parts.find_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            key.trim().eq_ignore_ascii_case("q").then(|| value.trim())
+        }) {
+            // NOTE: a malformed weight shouldn't be read as a preference
+            Some(q) => q.parse::<f32>().map_or(0.0, |q| q.clamp(0.0, 1.0)),
+            None => 1.0,
+        };
+
+        if name.eq_ignore_ascii_case(coding) {
+            return q;
+        } else if name == "*" {
+            wildcard = Some(q);
+        }
+    }
+
+    wildcard.unwrap_or(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::{CACHE_CONTROL, ETAG};
+
+    use super::*;
+
+    fn accepting(encoding: &'static str) -> HeaderMap {
+        HeaderMap::from_iter([(ACCEPT_ENCODING, HeaderValue::from_static(encoding))])
+    }
+
+    #[test]
+    fn hashed_path_is_cached_forever() {
+        let blob = WasmBlob::new(b"\0asm not really");
+
+        let response = blob.serve(&accepting("gzip"), None, true);
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(headers[VARY], "accept-encoding");
+        let cache_control = headers[CACHE_CONTROL].to_str().unwrap();
+        assert!(cache_control.contains("immutable"), "{cache_control}");
+
+        let response = blob.serve(&accepting("gzip"), None, false);
+        assert_eq!(response.headers()[CACHE_CONTROL], "no-cache");
+    }
+
+    #[test]
+    fn matching_etags_are_not_modified() {
+        let blob = WasmBlob::new(b"\0asm not really");
+        let response = blob.serve(&accepting("br"), None, true);
+        let etag: ETag = response.headers()[ETAG].to_str().unwrap().parse().unwrap();
+
+        let response = blob.serve(
+            &accepting("br"),
+            Some(IfNoneMatch::from(etag.clone())),
+            true,
+        );
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let headers = response.headers();
+        assert_eq!(headers[VARY], "accept-encoding");
+        assert!(
+            headers[CACHE_CONTROL]
+                .to_str()
+                .unwrap()
+                .contains("immutable")
+        );
+
+        // NOTE: the raw blob is a different representation than the brotli one
+        let response = blob.serve(&accepting("identity"), Some(IfNoneMatch::from(etag)), true);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    fn no_header_gets_the_raw_blob() {
+        assert_eq!(preferred_encoding(""), None);
+        assert_eq!(preferred_encoding("identity"), None);
+    }
+
+    #[test]
+    fn prefers_brotli() {
+        assert_eq!(preferred_encoding("gzip, deflate, br"), Some("br"));
+        assert_eq!(preferred_encoding("gzip"), Some("gzip"));
+        assert_eq!(preferred_encoding("GZIP;Q=0.5"), Some("gzip"));
+    }
+
+    #[test]
+    fn follows_weights() {
+        assert_eq!(preferred_encoding("br;q=0.5, gzip;q=0.8"), Some("gzip"));
+        assert_eq!(preferred_encoding("br;q=0.5, gzip"), Some("gzip"));
+        assert_eq!(preferred_encoding("br ; q = 0.9, gzip;q=0.8"), Some("br"));
+    }
+
+    #[test]
+    fn q_zero_refuses() {
+        assert_eq!(preferred_encoding("br;q=0, gzip"), Some("gzip"));
+        assert_eq!(preferred_encoding("br;q=0.000, gzip;q=0"), None);
+        assert_eq!(preferred_encoding("br;q=nope"), None);
+    }
+
+    #[test]
+    fn wildcard_covers_the_rest() {
+        assert_eq!(preferred_encoding("*"), Some("br"));
+        assert_eq!(preferred_encoding("br;q=0, *"), Some("gzip"));
+        assert_eq!(preferred_encoding("*;q=0"), None);
+        assert_eq!(preferred_encoding("gzip, *;q=0"), Some("gzip"));
+        assert_eq!(preferred_encoding("*;q=0.5, gzip"), Some("gzip"));
+    }
+}
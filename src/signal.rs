@@ -9,6 +9,7 @@ pub struct Signal<T: Clone, K: Clone + Hash + Eq> {
     pub(crate) inner: *mut SignalData<T, K>,
 }
 
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
 pub struct SignalData<T: Clone, K: Clone + Hash + Eq> {
     value: T,
     pub(crate) registered_dom_nodes: Vec<u32>,
@@ -29,7 +30,6 @@ impl<T: Clone, K: Clone + Hash + Eq> SignalData<T, K> {
 impl<T: Clone, K: Clone + Hash + Eq> Copy for Signal<T, K> {}
 
 impl<T: Clone, K: Clone + Hash + Eq> Signal<T, K> {
-    #[cfg(target_arch = "wasm32")]
     pub fn reset(&mut self) {
         // FIXME: yolo
         unsafe {
@@ -62,6 +62,7 @@ impl<T: Clone, K: Clone + Hash + Eq> Signal<T, K> {
         unsafe { (*self.inner).value.clone() }
     }
 
+    #[cfg_attr(not(target_arch = "wasm32"), allow(unused_variables))]
     pub fn get_with_key(&self, index: K) -> T {
         #[cfg(target_arch = "wasm32")]
         {
@@ -92,11 +93,11 @@ impl<T: Clone, K: Clone + Hash + Eq> Signal<T, K> {
         #[cfg(target_arch = "wasm32")]
         unsafe {
             for dom_id in (*self.inner).registered_dom_nodes.iter().cloned() {
-                PERSISTENT_VALUES.to_re_render.borrow_mut().insert(dom_id);
+                PERSISTENT_VALUES.with(|values| values.to_re_render.borrow_mut().insert(dom_id));
             }
 
             for (_, dom_id) in (*self.inner).registered_dom_nodes_by_key.iter() {
-                PERSISTENT_VALUES.to_re_render.borrow_mut().insert(*dom_id);
+                PERSISTENT_VALUES.with(|values| values.to_re_render.borrow_mut().insert(*dom_id));
             }
         }
     }
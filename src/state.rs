@@ -13,6 +13,9 @@ use crate::client::PERSISTENT_VALUES;
 pub struct StatefulClientEvent<T: Stateful, D: Serialize> {
     pub(crate) state_key: String,
     pub(crate) event: D,
+    /// Keyed updates only touch the entries they contain, so the server can merge them
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) keyed: bool,
 
     #[serde(skip)]
     _stateful: PhantomData<T>,
@@ -30,6 +33,7 @@ pub trait Stateful: Sized {
             event: serde_json::to_value(StatefulClientEvent {
                 state_key: Self::name().to_string(),
                 event: value,
+                keyed: false,
                 _stateful: PhantomData::<Self>,
             })
             .unwrap(),
@@ -39,6 +43,7 @@ pub trait Stateful: Sized {
 
 pub trait SettableEvent {
     fn as_any(&self) -> &dyn Any;
+    fn name(&self) -> &'static str;
     fn set(&mut self, value: serde_json::Value);
 }
 
@@ -124,6 +129,7 @@ where
             event: serde_json::to_value(StatefulClientEvent {
                 state_key: Self::name().to_string(),
                 event: data.into_iter().enumerate().collect::<Vec<(_, _)>>(),
+                keyed: true,
                 _stateful: PhantomData::<Self>,
             })
             .unwrap(),
@@ -139,6 +145,7 @@ where
             event: serde_json::to_value(StatefulClientEvent {
                 state_key: Self::name().to_string(),
                 event: vec![(key, value)],
+                keyed: true,
                 _stateful: PhantomData::<Self>,
             })
             .unwrap(),
@@ -187,6 +194,7 @@ where
 }
 
 #[derive(Clone, Copy)]
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
 pub struct StateInner<T: Stateful + Clone + 'static, M> {
     pub(crate) inner: T::Data,
     pub(crate) on_update: Option<fn(&T::Data)>,
@@ -225,6 +233,11 @@ where
         self
     }
 
+    fn name(&self) -> &'static str {
+        T::name()
+    }
+
+    #[cfg_attr(not(target_arch = "wasm32"), allow(unused_variables))]
     fn set(&mut self, value: serde_json::Value) {
         let keys = if let Ok(value) =
             serde_json::from_value::<StatefulClientEvent<T, T::Data>>(value.clone())
@@ -247,13 +260,15 @@ where
 
         #[cfg(target_arch = "wasm32")]
         {
-            if let Ok(mut to_re_render) = PERSISTENT_VALUES.to_re_render.try_borrow_mut() {
-                unsafe {
-                    for dom_id in (*self.data.inner).registered_dom_nodes.iter().cloned() {
-                        to_re_render.insert(dom_id);
+            PERSISTENT_VALUES.with(|values| {
+                if let Ok(mut to_re_render) = values.to_re_render.try_borrow_mut() {
+                    unsafe {
+                        for dom_id in (*self.data.inner).registered_dom_nodes.iter().cloned() {
+                            to_re_render.insert(dom_id);
+                        }
                     }
                 }
-            }
+            });
 
             if let Some(on_update) = self.data.get().on_update {
                 on_update(&self.data.get().inner);
@@ -273,6 +288,11 @@ where
         self
     }
 
+    fn name(&self) -> &'static str {
+        T::name()
+    }
+
+    #[cfg_attr(not(target_arch = "wasm32"), allow(unused_variables))]
     fn set(&mut self, value: serde_json::Value) {
         let keys = if let Ok(value) = serde_json::from_value::<
             StatefulClientEvent<T, MultipleValueUpdateArray<T::Data>>,
@@ -297,20 +317,22 @@ where
 
         #[cfg(target_arch = "wasm32")]
         {
-            if let Ok(mut to_re_render) = PERSISTENT_VALUES.to_re_render.try_borrow_mut() {
-                unsafe {
-                    for dom_id in (*self.data.inner).registered_dom_nodes.iter().cloned() {
-                        to_re_render.insert(dom_id);
-                    }
-                    for (_, dom_id) in (*self.data.inner)
-                        .registered_dom_nodes_by_key
-                        .iter()
-                        .filter(|(key, _)| keys.contains(*key))
-                    {
-                        to_re_render.insert(*dom_id);
+            PERSISTENT_VALUES.with(|values| {
+                if let Ok(mut to_re_render) = values.to_re_render.try_borrow_mut() {
+                    unsafe {
+                        for dom_id in (*self.data.inner).registered_dom_nodes.iter().cloned() {
+                            to_re_render.insert(dom_id);
+                        }
+                        for (_, dom_id) in (*self.data.inner)
+                            .registered_dom_nodes_by_key
+                            .iter()
+                            .filter(|(key, _)| keys.contains(*key))
+                        {
+                            to_re_render.insert(*dom_id);
+                        }
                     }
                 }
-            }
+            });
 
             if let Some(on_update) = self.data.get().on_update {
                 on_update(&self.data.get().inner);